@@ -8,8 +8,11 @@ use conc::mpmc::*;
 use data::dataloader::ID;
 use serde::{Serialize, Deserialize};
 use std::error::Error;
-use log::{info, error};
+use log::{info, warn, error};
 use data::RedisValue;
+use tokio::task::JoinHandle;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 
 pub struct PostID(ID);
@@ -189,6 +192,10 @@ pub enum Task {
 
 pub struct AnalyticsClient {
     sender: Sender<Task>,
+    receiver: Receiver<Task>,
+    workers: std::sync::Mutex<Vec<JoinHandle<()>>>,
+    num_workers: u32,
+    alive: Arc<AtomicU32>,
 }
 
 /*
@@ -228,24 +235,30 @@ impl<'a> AnalyticsOptions<'a> {
 
     pub async fn create(self) -> Result<AnalyticsClient, Box<dyn Error + Send + Sync>> {
         let (sender, receiver) = channel(self.max_pending_tasks);
+        let mut workers = Vec::with_capacity(self.num_workers as usize);
+        let alive = Arc::new(AtomicU32::new(0));
 
         for i in 0..self.num_workers {
             let worker = Worker{
+                alive: alive.clone(),
                 tasks: receiver.clone(),
                 db_conn: self.db_conn_pool.acquire().await?,
-                redis_conn: self.redis_conn_pool.conn().await
+                redis: self.redis_conn_pool.clone(),
             };
 
-            tokio::spawn(worker.run());
+            alive.fetch_add(1, Ordering::AcqRel);
+            workers.push(tokio::spawn(worker.run()));
         }
 
-        Ok(AnalyticsClient{ sender })
+        Ok(AnalyticsClient{ sender, receiver, workers: std::sync::Mutex::new(workers), num_workers: self.num_workers, alive })
     }
 }
 
 impl AnalyticsClient {
     pub async fn send_task(&self, task: Task) {
-        self.sender.clone().send(task).await;
+        if self.sender.send(task).await.is_err() {
+            warn!("Dropping analytics task, the queue is shut down");
+        }
     }
 
     pub async fn register_events(&self, token: String, events: Vec<AnalyticsEvent>) {
@@ -266,6 +279,37 @@ impl AnalyticsClient {
         })).await;
     }
 
+    pub fn workers_alive(&self) -> u32 {
+        self.alive.load(Ordering::Acquire)
+    }
+
+    pub fn num_workers(&self) -> u32 {
+        self.num_workers
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.receiver.len()
+    }
+
+    //stops accepting tasks and waits for the workers to finish the queued ones
+    pub async fn shutdown(&self, deadline: Duration) {
+        self.sender.close();
+
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+        let drained = tokio::time::timeout(deadline, async {
+            for worker in workers {
+                if let Err(e) = worker.await {
+                    error!("Analytics worker failed: {}", e);
+                }
+            }
+        }).await;
+
+        match drained {
+            Ok(()) => info!("Analytics queue drained"),
+            Err(_) => error!("Analytics queue was not drained within {}ms, remaining tasks are lost", deadline.as_millis()),
+        }
+    }
+
     //pub async fn navigate_to(&mut self, token: String, page: PageID) {
     //    self.navigate_to_with_events(token, page, Vec::new()).await;
     //}
@@ -273,9 +317,11 @@ impl AnalyticsClient {
 }
 
 pub struct Worker {
+    alive: Arc<AtomicU32>,
     tasks: Receiver<Task>,
     db_conn: PoolConnection<Postgres>,
-    redis_conn: RedisConnection,
+    //a connection is checked out per task, so a broken one is replaced by the pool instead of failing every later task
+    redis: RedisClient,
 }
 
 /*
@@ -350,13 +396,17 @@ fn current_page(token: &str) -> String {
     format!("analytics:timeline:{}:current", token)
 }
 
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.alive.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
 impl Worker {
     //todo could make this more data oriented by splitting []Event, into []ViewedBond, []ViewedPost, etc
     //however the benefits may be negible due to redis network transfer speeds
     pub async fn run(mut self) {
-        loop {
-            let task = self.tasks.recv().await;
-
+        while let Some(task) = self.tasks.recv_until_closed().await {
             if let Err(e) = self.perform_task(task).await {
                 error!("Analytics error: {}", e)
             }
@@ -365,6 +415,8 @@ impl Worker {
 
     async fn perform_task(&mut self, task: Task) -> Result<(), Box<dyn Error>> {
         println!("Analytics task : {:?}", task);
+        let mut redis_conn = self.redis.try_conn().await?;
+
         match task {
             Task::BeginSession(token, page) => {
                 let info = PageInfo{previous: None, next: None, page: page.id};
@@ -374,20 +426,20 @@ impl Worker {
                 redis::pipe()
                     .set(page_info(&token, 0), serde_json::to_string(&info)?)
                     .set(current_page(&token), 0 as i32)
-                    .query_async(&mut self.redis_conn).await?;
+                    .query_async(&mut redis_conn).await?;
             },
             Task::RegisterEvents(token, events) => {
-                let current : ID = self.redis_conn.get(current_page(&token)).await?;
+                let current : ID = redis_conn.get(current_page(&token)).await?;
                 let mut pipe = redis::pipe();
 
-                self.register_events_and_execute(&mut pipe, &page_events_key(&token, current), &events).await?;
+                Self::register_events_and_execute(&mut redis_conn, &mut pipe, &page_events_key(&token, current), &events).await?;
             },
             Task::RegisterEventsThenNavigateTo(token, events, page) => {
                 let current_page_key = current_page(&token);
-                let previous_id : ID = self.redis_conn.get(&current_page_key).await?;
+                let previous_id : ID = redis_conn.get(&current_page_key).await?;
 
                 let previous_page_key = page_info(&token, previous_id);
-                let previous : PageInfo = self.redis_conn.get(&previous_page_key).await?;
+                let previous : PageInfo = redis_conn.get(&previous_page_key).await?;
                 let current_id = previous_id + 1; //could cause problems if multiple workers execute the same cmd
                 let current = PageInfo{ previous: Some(previous_id), next: None, page: page.id };
                 let previous = PageInfo{ previous: previous.previous, page: previous.page, next: Some(current_id) };
@@ -398,21 +450,21 @@ impl Worker {
                     .set(page_info(&token, current_id), &current)
                     .set(current_page_key, current_id);
 
-                self.register_events_and_execute(&mut pipeline, &page_events_key(&token, previous_id), &events).await?;
+                Self::register_events_and_execute(&mut redis_conn, &mut pipeline, &page_events_key(&token, previous_id), &events).await?;
             }
         };
 
         Ok(())
     }
 
-    async fn register_events_and_execute(&mut self, pipe: &mut redis::Pipeline, page_events_key: &str, events: &[AnalyticsEvent]) -> Result<(), Box<dyn Error>> {
+    async fn register_events_and_execute(redis_conn: &mut RedisConnection, pipe: &mut redis::Pipeline, page_events_key: &str, events: &[AnalyticsEvent]) -> Result<(), Box<dyn Error>> {
         for event in events {
             println!("Register event : {:?}", event);
             let json = serde_json::to_string(&event)?;
             pipe.lpush(page_events_key, json);
         }
 
-        pipe.query_async(&mut self.redis_conn).await?;
+        pipe.query_async(redis_conn).await?;
         Ok(())
     }
 
@@ -7,11 +7,15 @@ use std::sync::Arc;
 use chrono::{Utc};
 use redis;
 use data::dataloader::ID;
+use tracing::Instrument;
+use crate::telemetry::sql_span;
 use crate::context::{get_shared, get_db, SharedContext, RedisClient, RedisConnection};
 use crate::analytics;
+use crate::errors;
 use redis::AsyncCommands;
 use redis::aio::ConnectionLike;
 
+#[derive(Clone)]
 pub struct Auth {
     pub user: ID,
     pub session_token: String,
@@ -21,7 +25,7 @@ pub struct Auth {
 pub fn get_auth<'a>(ctx: &'a Context<'a>) -> FieldResult<&'a Auth> {
     match ctx.data::<Auth>() {
         Ok(auth) => Ok(auth),
-        Err(_) => Err(FieldError("Authentication is required".to_string(), None))
+        Err(_) => Err(errors::unauthenticated("Authentication is required"))
     }
 }
 
@@ -46,9 +50,6 @@ fn create_session_token() -> String {
         .collect()
 }
 
-use std::time::Duration;
-const SESSION_EXPIRATION : usize = 60 * 60 * 24;
-
 //todo: Prevent too many sessions from being generated for repeated logins
 async fn begin_session(ctx: &SharedContext, account: ID, device_token: Option<String>) -> FieldResult<LoginResult> {
     let token = create_session_token();
@@ -56,14 +57,14 @@ async fn begin_session(ctx: &SharedContext, account: ID, device_token: Option<St
     let signed_in_at = Utc::now();
 
     let mut pipe = redis::pipe();
-    pipe.set_ex(&account_for_session(&token), account,SESSION_EXPIRATION);
+    pipe.set_ex(&account_for_session(&token), account, ctx.config.session.expiry_secs);
 
     if let Some(token) = device_token {
         pipe.lpush(&device_token_for_account(account), token);
     }
 
 
-    let mut redis = ctx.redis.conn().await;
+    let mut redis = ctx.redis.try_conn().await?;
     pipe.query_async(&mut redis).await?;
 
     ctx.analytics.begin_session(token.clone(), analytics::PageID::Home).await;
@@ -96,18 +97,19 @@ impl MutationAuth {
         let shared = get_shared(ctx);
         let found_user = query!("SELECT id, passwordhash FROM Users where username=$1", &username)
             .fetch_optional(&shared.db)
+            .instrument(sql_span("auth.login"))
             .await?;
 
         let stored_user = match found_user {
             Some(user) => user,
-            None => return Err(FieldError("No such username".to_string(), None))
+            None => return Err(errors::unauthenticated("No such username"))
         };
 
         let credentials_match = bcrypt::verify(password, &stored_user.passwordhash)?;
         if credentials_match {
             return begin_session(shared, stored_user.id, device_token).await;
         }
-        return Err(FieldError("Incorrect password".to_string(), None));
+        return Err(errors::unauthenticated("Incorrect password"));
     }
 
     pub async fn create_account(&self, ctx: &Context<'_>, form: CreateAccountForm) -> FieldResult<LoginResult> {
@@ -120,11 +122,12 @@ impl MutationAuth {
         RETURNING id
         ", form.username, password_hash, form.full_name, form.email, form.residence)
             .fetch_optional(get_db(ctx))
+            .instrument(sql_span("auth.create_account"))
             .await?;
 
         match created_account {
             Some(account) => begin_session(shared, account.id, form.device_token).await,
-            None => Err(FieldError("Username already exists".to_string(), None))
+            None => Err(errors::validation("Username already exists"))
         }
     }
 }
@@ -0,0 +1,174 @@
+use crate::context::{RedisClient, RedisConnection};
+use async_graphql::FieldResult;
+use log::{error, warn};
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+use redis::AsyncCommands;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+//how long a computation may hold the lock before another request is allowed to recompute
+const LOCK_EXPIRY_MS: usize = 2000;
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(20);
+//waiters poll for as long as the lock can be held, so a slow computation does not cause a stampede
+const LOCK_POLL_ATTEMPTS: usize = LOCK_EXPIRY_MS / LOCK_POLL_INTERVAL.as_millis() as usize;
+
+//only deletes the lock if it still holds our token, it may have expired and been taken by another request
+const RELEASE_SCRIPT: &str = "if redis.call('GET', KEYS[1]) == ARGV[1] then return redis.call('DEL', KEYS[1]) else return 0 end";
+
+fn lock_key(key: &str) -> String { format!("{}:lock", key) }
+
+fn lock_token() -> String {
+    thread_rng().sample_iter(&Alphanumeric).take(16).collect()
+}
+
+enum Lookup<T> {
+    Hit(T),
+    Miss,
+    Unavailable,
+}
+
+async fn lookup<T: DeserializeOwned>(redis: &mut RedisConnection, key: &str) -> Lookup<T> {
+    let value: Option<String> = match redis.get(key).await {
+        Ok(value) => value,
+        Err(e) => {
+            error!("Redis error reading cache key {}: {}", key, e);
+            return Lookup::Unavailable;
+        }
+    };
+
+    match value {
+        Some(json) => match serde_json::from_str(&json) {
+            Ok(value) => Lookup::Hit(value),
+            Err(e) => {
+                warn!("Discarding cache key {} with invalid value: {}", key, e);
+                Lookup::Miss
+            }
+        },
+        None => Lookup::Miss,
+    }
+}
+
+async fn try_lock(redis: &mut RedisConnection, key: &str, token: &str) -> redis::RedisResult<bool> {
+    let locked: Option<String> = redis::cmd("SET")
+        .arg(lock_key(key))
+        .arg(token)
+        .arg("NX")
+        .arg("PX")
+        .arg(LOCK_EXPIRY_MS)
+        .query_async(redis)
+        .await?;
+
+    Ok(locked.is_some())
+}
+
+async fn unlock(redis: &mut RedisConnection, key: &str, token: &str) {
+    let result: redis::RedisResult<i32> = redis::Script::new(RELEASE_SCRIPT)
+        .key(lock_key(key))
+        .arg(token)
+        .invoke_async(redis)
+        .await;
+
+    if let Err(e) = result {
+        error!("Redis error unlocking cache key {}: {}", key, e);
+    }
+}
+
+async fn store<T: Serialize>(redis: &mut RedisConnection, key: &str, expiry: usize, value: &T) {
+    let json = match serde_json::to_string(value) {
+        Ok(json) => json,
+        Err(e) => return error!("Could not serialize cache key {}: {}", key, e),
+    };
+
+    if let Err(e) = redis.set_ex::<_, _, ()>(key, json, expiry).await {
+        error!("Redis error writing cache key {}: {}", key, e);
+    }
+}
+
+async fn connect(client: &RedisClient, key: &str) -> Option<RedisConnection> {
+    match client.try_conn().await {
+        Ok(redis) => Some(redis),
+        Err(e) => {
+            warn!("Redis unavailable for cache key {}, computing without cache: {}", key, e);
+            None
+        }
+    }
+}
+
+//the lock is released whether or not the computation succeeded, so waiters can take over after an error
+//no connection is held while computing, a slow computation would otherwise starve the pool
+async fn compute_locked<T, F, Fut>(client: &RedisClient, key: &str, token: &str, expiry: usize, compute: F) -> FieldResult<T>
+    where T: Serialize,
+          F: FnOnce() -> Fut,
+          Fut: Future<Output = FieldResult<T>>,
+{
+    let result = compute().await;
+
+    //if redis went away the lock simply expires
+    if let Some(mut redis) = connect(client, key).await {
+        if let Ok(value) = &result {
+            store(&mut redis, key, expiry, value).await;
+        }
+        unlock(&mut redis, key, token).await;
+    }
+    result
+}
+
+/// Returns the cached value for `key`, or computes and stores it for `expiry` seconds.
+/// Only one request computes a missing key at a time, the others wait for its result.
+/// If redis is unavailable the value is computed without caching.
+pub async fn cached<T, F, Fut>(client: &RedisClient, key: &str, expiry: usize, compute: F) -> FieldResult<T>
+    where T: Serialize + DeserializeOwned,
+          F: FnOnce() -> Fut,
+          Fut: Future<Output = FieldResult<T>>,
+{
+    let token = lock_token();
+
+    for attempt in 0..=LOCK_POLL_ATTEMPTS {
+        if attempt > 0 {
+            delay_for(LOCK_POLL_INTERVAL).await;
+        }
+
+        //checked out per poll so waiters do not hold connections while sleeping
+        let mut redis = match connect(client, key).await {
+            Some(redis) => redis,
+            None => return compute().await,
+        };
+
+        match lookup(&mut redis, key).await {
+            Lookup::Hit(value) => return Ok(value),
+            Lookup::Unavailable => return compute().await,
+            Lookup::Miss => {}
+        }
+
+        //taken again while waiting, in case the request holding the lock failed
+        let locked = try_lock(&mut redis, key, &token).await;
+        drop(redis);
+
+        match locked {
+            Ok(true) => return compute_locked(client, key, &token, expiry, compute).await,
+            Ok(false) => {}
+            Err(e) => {
+                error!("Redis error locking cache key {}: {}", key, e);
+                return compute().await;
+            }
+        }
+    }
+
+    warn!("Timed out waiting for cache key {}, computing without cache", key);
+    compute().await
+}
+
+pub async fn invalidate(client: &RedisClient, keys: &[String]) {
+    let mut redis = match client.try_conn().await {
+        Ok(redis) => redis,
+        Err(e) => return error!("Redis unavailable, could not invalidate cache keys {:?}: {}", keys, e),
+    };
+
+    if let Err(e) = redis.del::<_, ()>(keys).await {
+        error!("Redis error invalidating cache keys {:?}: {}", keys, e);
+    }
+}
@@ -5,10 +5,13 @@ use sqlx::{query, query_as};
 use log::{info};
 use std::concat;
 use data::dataloader::{ID};
+use tracing::Instrument;
+use crate::telemetry::sql_span;
 use crate::dataloaders::get_loaders;
 use crate::context::*;
 use crate::auth::{get_auth, Auth};
 use crate::schema::Account;
+use crate::errors;
 
 
 enum ChatRole {
@@ -23,18 +26,26 @@ pub struct Group {
     profile: ID,
 }
 
-#[Object]
 impl Group {
-    async fn id(&self) -> ID { self.id }
-    async fn name(&self) -> &str { &self.groupname }
-    async fn profile(&self) -> ID { self.profile }
-    async fn member_count(&self, ctx: &Context<'_>) -> FieldResult<i32> {
+    async fn load_member_count(&self, ctx: &Context<'_>) -> FieldResult<i32> {
         let result = query!("SELECT COUNT(id) FROM GroupMembers WHERE chat = $1", self.id)
             .fetch_one(get_db(ctx))
+            .instrument(sql_span("chat.member_count"))
             .await?;
 
         Ok(result.count.unwrap_or(0) as i32)
     }
+}
+
+#[Object]
+impl Group {
+    async fn id(&self) -> ID { self.id }
+    async fn name(&self) -> &str { &self.groupname }
+    async fn profile(&self) -> ID { self.profile }
+    //null when counting failed, the error is returned next to the group
+    async fn member_count(&self, ctx: &Context<'_>) -> Option<i32> {
+        errors::nullable(ctx, self.load_member_count(ctx).await.map(Some))
+    }
 
     async fn members(&self, ctx: &Context<'_>) -> FieldResult<Vec<Account>> {
         let accounts = query_as!(Account, "SELECT Users.id, Users.username, Users.profile FROM GroupMembers
@@ -43,6 +54,7 @@ impl Group {
 
         ", self.id)
             .fetch_all(get_db(ctx))
+            .instrument(sql_span("chat.members"))
             .await?;
 
         Ok(accounts)
@@ -67,7 +79,9 @@ async fn get_messages(db: &DBClient, chat: ID) -> FieldResult<Vec<Message>> {
 	FROM Messages
 	INNER JOIN Users ON Messages.account = Users.ID
 	WHERE Messages.chat= $1", chat)
-        .fetch_all(db).await?
+        .fetch_all(db)
+        .instrument(sql_span("chat.get_messages"))
+        .await?
         .into_iter()
         .map(|result| Message{
             id: result.id,
@@ -123,6 +137,7 @@ impl QueryChats {
                 ELSE Users.id = DMS.user1
             END) WHERE (user1=$1 or user2=$1) and DMS.id=$2", auth.user, id)
                 .fetch_one(db)
+                .instrument(sql_span("chat.dm"))
                 .await?;
             Ok(DM{
                 id: id,
@@ -135,6 +150,7 @@ impl QueryChats {
         } else {
             let result = query!("SELECT id FROM DMS WHERE (user1=$1 or user2=$1) and id=$2", auth.user, id)
                 .fetch_one(db)
+                .instrument(sql_span("chat.dm"))
                 .await?;
 
             Ok(DM{
@@ -153,6 +169,7 @@ impl QueryChats {
 		    account = $2
 		)", id, auth.user)
             .fetch_one(get_db(ctx))
+            .instrument(sql_span("chat.group"))
             .await?;
 
         Ok(group)
@@ -170,6 +187,7 @@ impl QueryChats {
             END)
         WHERE user1=$1 or user2=$1", auth.user)
             .fetch_all(get_db(ctx))
+            .instrument(sql_span("chat.chats"))
             .await?;
 
         let groups = query_as!(Group, "SELECT id, groupname, profile FROM Groups
@@ -178,6 +196,7 @@ impl QueryChats {
 		    account = $1
 		)", auth.user)
             .fetch_all(get_db(ctx))
+            .instrument(sql_span("chat.chats"))
             .await?;
 
         let mut results = Vec::with_capacity(dms.len() + groups.len());
@@ -213,6 +232,7 @@ impl MutationChat {
         RETURNING ID
         ", auth.user, chat, mesg, sent)
             .fetch_one(get_db(ctx))
+            .instrument(sql_span("chat.send_message"))
             .await?;
 
         return Ok(mesg.id);
@@ -228,6 +248,7 @@ impl MutationChat {
         WHERE NOT EXISTS (SELECT * FROM DMs WHERE (user1 = $1 AND user2 = $2) or (user1 = $2 AND user2 = $1))
         RETURNING id", auth.user, account, created)
             .fetch_one(get_db(ctx))
+            .instrument(sql_span("chat.create_dm"))
             .await?;
 
         Ok(dm.id)
@@ -249,6 +270,7 @@ impl MutationChat {
         RETURNING ID
         ", group_name, 0, created)
             .fetch_one(db)
+            .instrument(sql_span("chat.create_group"))
             .await?;
 
 
@@ -271,14 +293,17 @@ impl MutationChat {
             .bind(created)
             .bind(group.id)
             .fetch_one(db)
+            .instrument(sql_span("chat.create_group"))
             .await {
             Ok(_) => Ok(group.id),
             Err(e) => {
                 let _ = query!("DELETE FROM Groups where id=$1 RETURNING id", group.id)
-                    .fetch_one(db).await?;
+                    .fetch_one(db)
+                    .instrument(sql_span("chat.create_group"))
+                    .await?;
 
                 info!("{}", e);
-                Err(FieldError("Adding members failed".to_string(), None))
+                Err(errors::validation("Adding members failed"))
             }
         }
 
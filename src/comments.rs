@@ -0,0 +1,168 @@
+use tracing::Instrument;
+use crate::telemetry::sql_span;
+use crate::context::{get_db, DBClient};
+use crate::auth::get_auth;
+use crate::errors;
+use crate::schema::Comment;
+use async_graphql::{Context, FieldResult};
+use async_graphql_derive::*;
+use data::dataloader::ID;
+use chrono::Utc;
+use sqlx::{query, query_as};
+
+const MAX_COMMENT_LEN: usize = 2000;
+const MAX_REASON_LEN: usize = 500;
+
+fn validate_mesg(mesg: &str) -> FieldResult<()> {
+    if mesg.trim().is_empty() { return Err(errors::validation("Comment must not be empty")) }
+    if mesg.chars().count() > MAX_COMMENT_LEN { return Err(errors::validation(&format!("Comments are limited to {} characters", MAX_COMMENT_LEN))) }
+    Ok(())
+}
+
+async fn load_comment(db: &DBClient, id: ID) -> FieldResult<Comment> {
+    query_as!(Comment, "SELECT id, account, post, parent, mesg, sent, edited, hidden FROM Comments WHERE id = $1", id)
+        .fetch_optional(db)
+        .instrument(sql_span("comments.load_comment"))
+        .await?
+        .ok_or_else(|| errors::not_found("No such comment"))
+}
+
+async fn post_author(db: &DBClient, post: ID) -> FieldResult<ID> {
+    let post = query!("SELECT account FROM Posts WHERE id = $1", post)
+        .fetch_optional(db)
+        .instrument(sql_span("comments.post_author"))
+        .await?
+        .ok_or_else(|| errors::not_found("No such post"))?;
+
+    Ok(post.account)
+}
+
+//hidden comments stay in the database, so the post author can review and unhide them
+async fn set_hidden(ctx: &Context<'_>, id: ID, hidden: bool) -> FieldResult<Comment> {
+    let auth = get_auth(ctx)?;
+    let db = get_db(ctx);
+
+    let comment = load_comment(db, id).await?;
+    if post_author(db, comment.post).await? != auth.user {
+        return Err(errors::forbidden("Only the author of the post can hide comments"));
+    }
+
+    let comment = query_as!(Comment, "UPDATE Comments SET hidden = $2 WHERE id = $1
+    RETURNING id, account, post, parent, mesg, sent, edited, hidden", id, hidden)
+        .fetch_one(db)
+        .instrument(sql_span("comments.set_hidden"))
+        .await?;
+
+    Ok(comment)
+}
+
+#[derive(Default)]
+pub struct MutationComments;
+
+#[Object]
+impl MutationComments {
+    //replies are comments with a parent, which has to be a visible comment on the same post
+    async fn add_comment(&self, ctx: &Context<'_>, post: ID, mesg: String, parent: Option<ID>) -> FieldResult<Comment> {
+        let auth = get_auth(ctx)?;
+        let db = get_db(ctx);
+
+        validate_mesg(&mesg)?;
+        post_author(db, post).await?;
+
+        if let Some(parent) = parent {
+            let parent = load_comment(db, parent).await?;
+            if parent.post != post || parent.hidden {
+                return Err(errors::not_found("No such comment"));
+            }
+        }
+
+        let comment = query_as!(Comment, "INSERT INTO Comments (account, post, parent, mesg, sent, hidden)
+        VALUES ($1, $2, $3, $4, $5, false)
+        RETURNING id, account, post, parent, mesg, sent, edited, hidden", auth.user, post, parent, mesg, Utc::now())
+            .fetch_one(db)
+            .instrument(sql_span("comments.add_comment"))
+            .await?;
+
+        Ok(comment)
+    }
+
+    async fn edit_comment(&self, ctx: &Context<'_>, id: ID, mesg: String) -> FieldResult<Comment> {
+        let auth = get_auth(ctx)?;
+        let db = get_db(ctx);
+
+        validate_mesg(&mesg)?;
+        if load_comment(db, id).await?.account != auth.user {
+            return Err(errors::forbidden("Only the author can edit this comment"));
+        }
+
+        let comment = query_as!(Comment, "UPDATE Comments SET mesg = $2, edited = $3 WHERE id = $1
+        RETURNING id, account, post, parent, mesg, sent, edited, hidden", id, mesg, Utc::now())
+            .fetch_one(db)
+            .instrument(sql_span("comments.edit_comment"))
+            .await?;
+
+        Ok(comment)
+    }
+
+    //deletes the comment with all of its replies, by its author or the author of the post
+    async fn delete_comment(&self, ctx: &Context<'_>, id: ID) -> FieldResult<ID> {
+        let auth = get_auth(ctx)?;
+        let db = get_db(ctx);
+
+        let comment = load_comment(db, id).await?;
+        if comment.account != auth.user && post_author(db, comment.post).await? != auth.user {
+            return Err(errors::forbidden("Only the author of the comment or the post can delete this comment"));
+        }
+
+        let mut tx = db.begin().await?;
+        query!("WITH RECURSIVE thread AS (
+            SELECT id FROM Comments WHERE id = $1
+            UNION SELECT Comments.id FROM Comments INNER JOIN thread ON Comments.parent = thread.id
+        )
+        DELETE FROM CommentReports WHERE comment IN (SELECT id FROM thread)", id)
+            .execute(&mut tx)
+            .instrument(sql_span("comments.delete_comment"))
+            .await?;
+        query!("WITH RECURSIVE thread AS (
+            SELECT id FROM Comments WHERE id = $1
+            UNION SELECT Comments.id FROM Comments INNER JOIN thread ON Comments.parent = thread.id
+        )
+        DELETE FROM Comments WHERE id IN (SELECT id FROM thread)", id)
+            .execute(&mut tx)
+            .instrument(sql_span("comments.delete_comment"))
+            .await?;
+        tx.commit().await?;
+
+        Ok(id)
+    }
+
+    //reports show up in reportedComments of the post, reporting twice is a no op.
+    //CommentReports has a unique key on (comment, account), so concurrent reports can not insert duplicates
+    async fn report_comment(&self, ctx: &Context<'_>, id: ID, reason: String) -> FieldResult<bool> {
+        let auth = get_auth(ctx)?;
+        let db = get_db(ctx);
+
+        if reason.chars().count() > MAX_REASON_LEN {
+            return Err(errors::validation(&format!("Reasons are limited to {} characters", MAX_REASON_LEN)));
+        }
+        if load_comment(db, id).await?.account == auth.user {
+            return Err(errors::validation("You can not report your own comment"));
+        }
+
+        query!("INSERT INTO CommentReports (comment, account, reason, reported) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (comment, account) DO NOTHING", id, auth.user, reason, Utc::now())
+            .execute(db)
+            .instrument(sql_span("comments.report_comment"))
+            .await?;
+
+        Ok(true)
+    }
+
+    async fn hide_comment(&self, ctx: &Context<'_>, id: ID) -> FieldResult<Comment> {
+        set_hidden(ctx, id, true).await
+    }
+
+    async fn unhide_comment(&self, ctx: &Context<'_>, id: ID) -> FieldResult<Comment> {
+        set_hidden(ctx, id, false).await
+    }
+}
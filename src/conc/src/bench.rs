@@ -62,7 +62,7 @@ mod tests {
 
                         tokio::spawn(async move {
                             for i in 0..BUFFER_SIZE * BUFFER_MUL {
-                                sender.send(i).await;
+                                sender.send(i).await.unwrap();
                             }
                         });
 
@@ -98,11 +98,58 @@ mod tests {
 
            tokio::spawn(async move {
                for i in 0..10 {
-                   send.send(i).await;
+                   send.send(i).await.unwrap();
                }
            });
 
            tokio::join!(join);
        })
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_mpmc_close_drains() {
+        tokio_test::block_on(async {
+            let (send, recv) = mpmc::channel(4);
+
+            for i in 0..3 {
+                send.send(i).await.unwrap();
+            }
+            send.close();
+
+            for i in 0..3 {
+                assert_eq!(recv.recv_until_closed().await, Some(i));
+            }
+            assert_eq!(recv.recv_until_closed().await, None);
+        })
+    }
+
+    #[test]
+    fn test_mpmc_send_after_close_fails() {
+        tokio_test::block_on(async {
+            let (send, recv) = mpmc::channel(1);
+
+            send.send(1).await.unwrap();
+            send.close();
+
+            assert_eq!(send.send(2).await, Err(SendError(2)));
+            assert_eq!(recv.recv_until_closed().await, Some(1));
+            assert_eq!(recv.recv_until_closed().await, None);
+        })
+    }
+
+    #[test]
+    fn test_mpmc_close_wakes_parked_receiver() {
+        let mut runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
+
+        runtime.block_on(async {
+            let (send, recv) = mpmc::channel::<u32>(2);
+
+            let receiver = tokio::spawn(async move { recv.recv_until_closed().await });
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+            send.close();
+
+            let received = tokio::time::timeout(std::time::Duration::from_secs(1), receiver).await;
+            assert_eq!(received.expect("receiver stayed parked after close").unwrap(), None);
+        })
+    }
+}
@@ -25,7 +25,7 @@ fn main() {
 
                 tokio::spawn(async move {
                     for i in 0..BUFFER_SIZE * BUFFER_MUL {
-                        sender.send(i).await;
+                        sender.send(i).await.unwrap();
                     }
                 });
 
@@ -189,6 +189,7 @@ pub struct Internal<T: Send> {
     pub head: Wrapping<u32>,
     pub tail: Wrapping<u32>,
     pub mask: u32,
+    pub closed: bool,
     /*read_at: atomic::AtomicU32,
     head: atomic::AtomicU32,
     write_at: atomic::AtomicU32,
@@ -229,22 +230,41 @@ impl<T: Send> Clone for Sender<T> {
     }
 }
 
+/// Returned by `send` once the channel is closed, hands the value back to the caller.
+#[derive(Debug, PartialEq)]
+pub struct SendError<T>(pub T);
+
+enum SendState<T> {
+    Sent,
+    Waiting,
+    Full(T),
+    Closed(T),
+}
+
 impl<T: Send> Sender<T> {
-    fn send_or_add_to_waitlist(&self, waker: Option<Waker>, value: T) -> bool {
+    //closed is checked under the same lock as the queue, so no value is accepted after close
+    fn send_or_add_to_waitlist(&self, waker: Option<Waker>, value: T) -> SendState<T> {
         let mut internal = self.internal.as_ref().lock().unwrap();
         let n = internal.queue.len() as u32; //should never resize
 
+        if internal.closed {
+            return SendState::Closed(value)
+        }
+
         let is_full = (internal.head - internal.tail).0 >= n;
 
         if is_full {
-            if let Some(waker) = waker {
-                internal.senders.push((value, waker)); //remove clone somehow
+            match waker {
+                Some(waker) => {
+                    internal.senders.push((value, waker)); //remove clone somehow
 
-                if let Some(waker) = internal.receivers.pop() {
-                    waker.wake();
-                }
+                    if let Some(waker) = internal.receivers.pop() {
+                        waker.wake();
+                    }
 
-                return false
+                    return SendState::Waiting
+                }
+                None => return SendState::Full(value),
             }
         }
 
@@ -259,28 +279,40 @@ impl<T: Send> Sender<T> {
             waker.wake();
         }
 
-        return true
+        return SendState::Sent
     }
 
-    pub fn try_send(&mut self, value: T) -> bool { //returns true if sent succeded
-        self.send_or_add_to_waitlist(None, value)
+    //receivers using recv_until_closed will drain the remaining values, then return None
+    pub fn close(&self) {
+        let mut internal = self.internal.as_ref().lock().unwrap();
+        internal.closed = true;
+
+        for waker in mem::take(&mut internal.receivers) {
+            waker.wake();
+        }
     }
 
-    pub async fn send(&self, value: T) {
-        //let first = AtomicBool::new(true);
+    pub fn try_send(&mut self, value: T) -> bool { //returns true if sent succeded, false if full or closed
+        match self.send_or_add_to_waitlist(None, value) {
+            SendState::Sent => true,
+            _ => false,
+        }
+    }
 
+    //a sender already waiting for space when the channel closes still has its value drained
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
         let mut first = Some(value);
 
         tokio::future::poll_fn(|ctx| {
             let value = match first.take() {
                 Some(value) => value,
-                None => return Poll::Ready(())
+                None => return Poll::Ready(Ok(()))
             };
 
-            if self.send_or_add_to_waitlist(Some(ctx.waker().clone()), value) {
-                Poll::Ready(())
-            } else {
-                Poll::Pending //check if executed twice
+            match self.send_or_add_to_waitlist(Some(ctx.waker().clone()), value) {
+                SendState::Sent => Poll::Ready(Ok(())),
+                SendState::Waiting => Poll::Pending, //woken once a receiver took the value
+                SendState::Closed(value) | SendState::Full(value) => Poll::Ready(Err(SendError(value))),
             }
         }).await
     }
@@ -295,21 +327,33 @@ impl<T: Send> Clone for Receiver<T> {
 
 impl<T: Send> Receiver<T> {
     pub fn recv_or_add_to_waitlist(&self, waker: Option<Waker>) -> Option<T> {
+        match self.poll_recv(waker) {
+            Poll::Ready(value) => value,
+            Poll::Pending => None,
+        }
+    }
+
+    //Ready(None) once the channel is closed and drained, closed is checked under the same lock as the
+    //waitlist registration so a close can not slip in between and leave the receiver parked
+    fn poll_recv(&self, waker: Option<Waker>) -> Poll<Option<T>> {
         let mut internal = self.internal.as_ref().lock().unwrap();
-        let n = internal.queue.len() as u32; //should never resize
 
         let is_empty = internal.head == internal.tail; // (head - tail).0 == 0;
         if is_empty {
             if let Some((value, waker)) = internal.senders.pop() {
                 waker.wake();
-                return Some(value)
+                return Poll::Ready(Some(value))
+            }
+
+            if internal.closed {
+                return Poll::Ready(None)
             }
 
             if let Some(waker) = waker {
                 internal.receivers.push(waker);
             }
 
-            return None
+            return Poll::Pending
         }
 
         let tail = (internal.tail.0 & internal.mask) as usize;
@@ -318,13 +362,27 @@ impl<T: Send> Receiver<T> {
         internal.tail += Wrapping(1);
 
         match value {
-            Some(value) => Some(value),
+            Some(value) => Poll::Ready(Some(value)),
             None => {
                 panic!("Should never happen!!!!!");
             }
         }
     }
 
+    //values buffered in the channel plus senders waiting for space
+    pub fn len(&self) -> usize {
+        let internal = self.internal.as_ref().lock().unwrap();
+        (internal.head - internal.tail).0 as usize + internal.senders.len()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.internal.as_ref().lock().unwrap().closed
+    }
+
+    pub async fn recv_until_closed(&self) -> Option<T> {
+        tokio::future::poll_fn(|ctx| self.poll_recv(Some(ctx.waker().clone()))).await
+    }
+
     pub async fn recv(&self) -> T {
         tokio::future::poll_fn(|ctx| {
             match self.recv_or_add_to_waitlist(Some(ctx.waker().clone())) {
@@ -354,7 +412,8 @@ pub fn channel<T: Send>(bounded: u32) -> (Sender<T>, Receiver<T>) {
         receivers: vec![],
         tail: Wrapping(0),
         head: Wrapping(0),
-        mask: bounded - 1
+        mask: bounded - 1,
+        closed: false,
     }));
 
     (Sender{internal: internal.clone()}, Receiver{internal: internal.clone()})
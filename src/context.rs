@@ -3,7 +3,9 @@ use async_graphql::{Schema, EmptySubscription};
 use std::sync::{Arc, Mutex}; //might be a better idea to use tokio::Mutex, since it is non blocking, depends on contention really
 use std::error::Error;
 use std::default::Default;
-use sqlx::postgres::{PgPoolOptions, Postgres};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions, Postgres};
+use sqlx::ConnectOptions;
+use std::str::FromStr;
 use sqlx::Pool;
 use tokio::sync::mpsc::{Receiver, Sender, channel};
 use dotenv;
@@ -11,6 +13,10 @@ use std::task::{Waker, Poll, Context};
 use std::future::Future;
 use std::pin::Pin;
 use redis::AsyncCommands;
+use std::time::Duration;
+use tokio::time::delay_for;
+use log::{info, warn, error};
+use tracing::{debug_span, Instrument};
 
 
 pub type DBClient = Pool<Postgres>;
@@ -20,8 +26,17 @@ type InitResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
 use std::sync::atomic::AtomicI32;
 use redis::{RedisFuture, Cmd, Pipeline};
 use crate::analytics::{AnalyticsClient, AnalyticsOptions};
+use crate::metrics::Metrics;
+use crate::image_store::{ImageStore, make_image_store};
+use crate::image::VariantLocks;
+use crate::moderation::{ModerationProvider, make_moderation_provider};
+use crate::config::{Config, DatabaseConfig, RedisConfig, AnalyticsConfig};
 
 const MAX_CONNECTIONS : usize= 3;
+const MIN_RECONNECT_BACKOFF : Duration = Duration::from_millis(50);
+const MAX_RECONNECT_BACKOFF : Duration = Duration::from_secs(5);
+//used when the pool was built without an acquire timeout, so a checkout can never hang forever
+const DEFAULT_ACQUIRE_TIMEOUT : Duration = Duration::from_secs(5);
 
 struct RedisConnectionFutureInternal {
     conn: Option<redis::aio::Connection>,
@@ -35,30 +50,67 @@ struct RedisConnectionFuture {
 
 
 pub struct RedisClientPool {
+    client: redis::Client,
     connections: Vec<redis::aio::Connection>,
     futures: Vec<RedisConnectionFuture>,
+    in_use: usize,
+    reconnecting: usize,
+    closed: bool,
 }
 
-
+#[derive(Debug, Clone, Copy)]
+pub struct RedisPoolStats {
+    pub idle: usize,
+    pub in_use: usize,
+    pub waiters: usize,
+    pub reconnecting: usize,
+}
 
 /*impl Send for RedisClientPool {}*/
 
+#[derive(Clone)]
 pub struct RedisClient {
     pool: Arc<Mutex<RedisClientPool>>,
+    test_on_checkout: bool,
+    acquire_timeout: Option<Duration>,
 }
 
 pub struct RedisConnection {
     pool: Arc<Mutex<RedisClientPool>>,
     conn: Option<redis::aio::Connection>,
+    broken: bool,
+}
+
+//io errors mean the connection itself is unusable, anything else is a problem with the command
+fn is_broken(e: &redis::RedisError) -> bool {
+    e.is_io_error() || e.is_connection_dropped() || e.is_connection_refusal() || e.is_timeout()
 }
 
 impl redis::aio::ConnectionLike for RedisConnection {
     fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, redis::Value> {
-        self.conn.as_mut().unwrap().req_packed_command(cmd)
+        let broken = &mut self.broken;
+        let conn = self.conn.as_mut().unwrap();
+
+        Box::pin(async move {
+            let result = conn.req_packed_command(cmd).await;
+            if let Err(e) = &result {
+                *broken |= is_broken(e);
+            }
+            result
+        }.instrument(debug_span!("redis")))
     }
 
     fn req_packed_commands<'a>(&'a mut self, cmd: &'a Pipeline, offset: usize, count: usize) -> RedisFuture<'a, Vec<redis::Value>> {
-        self.conn.as_mut().unwrap().req_packed_commands(cmd, offset, count)
+        let broken = &mut self.broken;
+        let conn = self.conn.as_mut().unwrap();
+
+        Box::pin(async move {
+            let result = conn.req_packed_commands(cmd, offset, count).await;
+            if let Err(e) = &result {
+                *broken |= is_broken(e);
+            }
+            result
+        }.instrument(debug_span!("redis_pipeline", count)))
     }
 
     fn get_db(&self) -> i64 {
@@ -81,11 +133,55 @@ impl RedisClient {
 
 impl Drop for RedisConnection {
     fn drop(&mut self) {
-        self.pool.lock().unwrap().release(self.conn.take().unwrap())
+        let conn = self.conn.take().unwrap();
+        let mut pool = self.pool.lock().unwrap();
+        pool.in_use -= 1;
+
+        if pool.closed {
+            return;
+        }
+
+        if self.broken {
+            pool.reconnecting += 1;
+            tokio::spawn(reconnect(self.pool.clone()));
+        } else {
+            pool.release(conn)
+        }
     }
 }
 
+//replaces a broken connection, retrying with exponential backoff until redis is reachable again or the pool is closed
+async fn reconnect(pool: Arc<Mutex<RedisClientPool>>) {
+    let client = pool.lock().unwrap().client.clone();
+    let mut backoff = MIN_RECONNECT_BACKOFF;
+
+    loop {
+        {
+            let mut pool = pool.lock().unwrap();
+            if pool.closed {
+                pool.reconnecting -= 1;
+                return;
+            }
+        }
 
+        match client.get_async_connection().await {
+            Ok(conn) => {
+                let mut pool = pool.lock().unwrap();
+                pool.reconnecting -= 1;
+                if !pool.closed {
+                    info!("Reconnected to redis");
+                    pool.release(conn);
+                }
+                return;
+            }
+            Err(e) => {
+                error!("Could not reconnect to redis, retrying in {}ms: {}", backoff.as_millis(), e);
+                delay_for(backoff).await;
+                backoff = std::cmp::min(backoff * 2, MAX_RECONNECT_BACKOFF);
+            }
+        }
+    }
+}
 
 impl Unpin for RedisConnectionFuture {}
 
@@ -107,17 +203,22 @@ impl Future for RedisConnectionFuture {
 }
 
 impl RedisClientPool {
+    //waiters that were dropped without cancelling hold the only reference to their future, so they are skipped
     fn release(&mut self, conn: redis::aio::Connection) {
-        if let Some(future) = self.futures.pop() {
-            let internal = &mut future.internal.lock().unwrap();
+        while let Some(future) = self.futures.pop() {
+            if Arc::strong_count(&future.internal) == 1 {
+                continue;
+            }
 
+            let internal = &mut future.internal.lock().unwrap();
             internal.conn = Some(conn);
             if let Some(waker) = internal.waker.take() {
                 waker.wake()
             }
-        } else {
-            self.connections.push(conn);
+            return;
         }
+
+        self.connections.push(conn);
     }
 
     fn acquire(&mut self) -> RedisConnectionFuture {
@@ -133,25 +234,146 @@ impl RedisClientPool {
             }
         }
     }
+
+    //a timed out waiter may already have been handed a connection, which has to go back to the pool
+    fn cancel(&mut self, future: &RedisConnectionFuture) {
+        self.futures.retain(|waiting| !Arc::ptr_eq(&waiting.internal, &future.internal));
+
+        let conn = future.internal.lock().unwrap().conn.take();
+        if let Some(conn) = conn {
+            self.release(conn);
+        }
+    }
 }
 
-impl RedisClient {
-    pub async fn pool(client: redis::Client, num_connections: usize) -> redis::RedisResult<RedisClient> {
-        let mut connections = Vec::with_capacity(num_connections);
+//cancels the wait when the caller stops waiting for any reason, e.g. a request timeout or a disconnected client,
+//so a connection is never handed to a waiter that is gone
+struct AcquireGuard<'a> {
+    pool: &'a Mutex<RedisClientPool>,
+    acquire: RedisConnectionFuture,
+}
+
+impl Drop for AcquireGuard<'_> {
+    fn drop(&mut self) {
+        self.pool.lock().unwrap().cancel(&self.acquire);
+    }
+}
+
+pub struct RedisPoolOptions {
+    client: redis::Client,
+    max_connections: usize,
+    test_on_checkout: bool,
+    acquire_timeout: Option<Duration>,
+}
 
-        for _ in 0..num_connections {
-           connections.push(client.get_async_connection().await?);
+impl RedisPoolOptions {
+    pub fn new(client: redis::Client) -> RedisPoolOptions {
+        RedisPoolOptions {
+            client,
+            max_connections: MAX_CONNECTIONS,
+            test_on_checkout: false,
+            acquire_timeout: None,
+        }
+    }
+
+    pub fn max_connections(mut self, connections: usize) -> Self {
+        self.max_connections = connections;
+        self
+    }
+
+    pub fn test_on_checkout(mut self, test: bool) -> Self {
+        self.test_on_checkout = test;
+        self
+    }
+
+    pub fn acquire_timeout(mut self, timeout: Duration) -> Self {
+        self.acquire_timeout = Some(timeout);
+        self
+    }
+
+    pub async fn create(self) -> redis::RedisResult<RedisClient> {
+        let mut connections = Vec::with_capacity(self.max_connections);
+
+        for _ in 0..self.max_connections {
+           connections.push(self.client.get_async_connection().await?);
         }
 
         Ok(RedisClient{
-            pool: Arc::new(Mutex::new(RedisClientPool{ connections, futures: Vec::new()})),
+            pool: Arc::new(Mutex::new(RedisClientPool{
+                client: self.client,
+                connections,
+                futures: Vec::new(),
+                in_use: 0,
+                reconnecting: 0,
+                closed: false,
+            })),
+            test_on_checkout: self.test_on_checkout,
+            acquire_timeout: self.acquire_timeout,
         })
     }
+}
+
+impl RedisClient {
+    pub async fn pool(client: redis::Client, num_connections: usize) -> redis::RedisResult<RedisClient> {
+        RedisPoolOptions::new(client)
+            .max_connections(num_connections)
+            .create().await
+    }
+
+    pub fn stats(&self) -> RedisPoolStats {
+        let pool = self.pool.lock().unwrap();
+
+        RedisPoolStats {
+            idle: pool.connections.len(),
+            in_use: pool.in_use,
+            waiters: pool.futures.len(),
+            reconnecting: pool.reconnecting,
+        }
+    }
 
-    pub async fn conn(&self) -> RedisConnection {
-        let acquire = self.pool.lock().unwrap().acquire();
+    //waits for a connection for at most the acquire timeout, including connections discarded by the checkout PING
+    pub async fn try_conn(&self) -> redis::RedisResult<RedisConnection> {
+        let deadline = tokio::time::Instant::now() + self.acquire_timeout.unwrap_or(DEFAULT_ACQUIRE_TIMEOUT);
+
+        loop {
+            let guard = {
+                let mut pool = self.pool.lock().unwrap();
+                if pool.closed {
+                    return Err(redis::RedisError::from((redis::ErrorKind::IoError, "Redis connection pool is closed")));
+                }
+                AcquireGuard { pool: &self.pool, acquire: pool.acquire() }
+            };
+
+            //cancelling after the connection was taken is a no op
+            let conn = match tokio::time::timeout_at(deadline, guard.acquire.clone()).await {
+                Ok(conn) => conn,
+                Err(_) => return Err(redis::RedisError::from((redis::ErrorKind::IoError, "Timed out acquiring redis connection"))),
+            };
+            drop(guard);
+
+            self.pool.lock().unwrap().in_use += 1;
+            let mut conn = RedisConnection { pool: self.pool.clone(), conn: Some(conn), broken: false };
+
+            if !self.test_on_checkout {
+                return Ok(conn);
+            }
+
+            //dropping a broken connection schedules a reconnect
+            match redis::cmd("PING").query_async::<_, ()>(&mut conn).await {
+                Ok(()) => return Ok(conn),
+                Err(e) => {
+                    warn!("Discarding redis connection that failed PING: {}", e);
+                    conn.broken = true;
+                }
+            }
+        }
+    }
 
-        RedisConnection { pool: self.pool.clone(), conn: Some(acquire.await) }
+    //drops the idle connections, connections still in use are closed when they are returned
+    pub fn close(&self) {
+        let mut pool = self.pool.lock().unwrap();
+        pool.closed = true;
+        pool.connections.clear();
     }
 }
 
@@ -160,6 +382,11 @@ pub struct SharedContext {
     pub redis: RedisClient,
     pub schema: APISchema,
     pub analytics: AnalyticsClient,
+    pub metrics: Metrics,
+    pub images: Arc<dyn ImageStore>,
+    pub moderation: Arc<dyn ModerationProvider>,
+    pub variant_locks: VariantLocks,
+    pub config: Config,
 }
 
 
@@ -171,49 +398,111 @@ pub fn get_db<'a>(ctx: &'a async_graphql::Context<'_>) -> &'a DBClient {
     &get_shared(ctx).db
 }
 pub fn get_analytics<'a>(ctx: &'a async_graphql::Context<'_>) -> &'a AnalyticsClient { &get_shared(ctx).analytics }
-pub async fn get_redis_conn(ctx: &async_graphql::Context<'_>) -> RedisConnection { get_shared(ctx).redis.conn().await }
-
-//make number of connections an environment variable
-async fn make_db() -> Result<DBClient, sqlx::Error> {
-    let url = match dotenv::var("DATABASE_URL") {
-        Ok(url) => url,
-        Err(e) => return Err(sqlx::Error::Configuration(Box::from(e))),
-    };
+pub fn get_redis<'a>(ctx: &'a async_graphql::Context<'_>) -> &'a RedisClient { &get_shared(ctx).redis }
+pub async fn get_redis_conn(ctx: &async_graphql::Context<'_>) -> redis::RedisResult<RedisConnection> { get_shared(ctx).redis.try_conn().await }
 
+pub async fn make_db(config: &DatabaseConfig) -> Result<DBClient, sqlx::Error> {
     println!("Connecting to database!");
 
+    //statements are logged with their literals, which can include personal data
+    let mut options = PgConnectOptions::from_str(&config.url)?;
+    options.log_statements(log::LevelFilter::Off);
+
     let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect_timeout(std::time::Duration::from_millis(100)) //todo: this should not be necessary
-        .connect(&url).await?;
+        .max_connections(config.max_connections)
+        .connect_timeout(config.connect_timeout())
+        .connect_with(options).await?;
 
     println!("Sucessfully connected!");
 
     Ok(pool)
 }
 
-async fn make_redis() -> InitResult<RedisClient> {
-    let url = dotenv::var("REDIS_URL")?;
-
-    let client = redis::Client::open(url)?;
-    Ok(RedisClient::pool(client, 5).await?)
+pub async fn make_redis(config: &RedisConfig) -> InitResult<RedisClient> {
+    let client = redis::Client::open(config.url.as_str())?;
+    Ok(RedisPoolOptions::new(client)
+        .max_connections(config.max_connections)
+        .test_on_checkout(config.test_on_checkout)
+        .acquire_timeout(config.acquire_timeout())
+        .create().await?)
 }
 
 
-async fn make_analytics(db: &DBClient, redis: &RedisClient) -> InitResult<AnalyticsClient> {
+async fn make_analytics(config: &AnalyticsConfig, db: &DBClient, redis: &RedisClient) -> InitResult<AnalyticsClient> {
     AnalyticsOptions::new(db, redis)
-        .max_pending_tasks(16)
-        .num_workers(1)
+        .max_pending_tasks(config.max_pending_tasks)
+        .num_workers(config.workers)
         .create().await
 }
 
-pub async fn make_shared_context() -> InitResult<Arc<SharedContext>> {
-    let db = make_db().await?;
-    let redis = make_redis().await?;
-    let analytics = make_analytics(&db, &redis).await?;
+pub async fn make_shared_context(config: Config) -> InitResult<Arc<SharedContext>> {
+    let db = make_db(&config.database).await?;
+    let redis = make_redis(&config.redis).await?;
+    let analytics = make_analytics(&config.analytics, &db, &redis).await?;
+    let metrics = Metrics::new()?;
+    let images = make_image_store(config.images.storage, &config.images, &db)?;
+    let moderation = make_moderation_provider(&config.moderation, config.images.max_upload_bytes);
 
     Ok(Arc::new(SharedContext {
-        db, redis, analytics,
-        schema: Schema::build(QueryRoot::default(), MutationRoot::default(), EmptySubscription).finish(),
+        db, redis, analytics, metrics, images, moderation, config,
+        variant_locks: VariantLocks::default(),
+        schema: Schema::build(QueryRoot::default(), MutationRoot::default(), EmptySubscription)
+            .extension(|| async_graphql::extensions::Tracing::default())
+            .finish(),
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //redis only sends commands on connect when a password or database is set, so accepting is enough
+    async fn fake_redis() -> redis::Client {
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        redis::Client::open(format!("redis://{}", addr).as_str()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn dropped_waiter_does_not_leak_the_connection() {
+        let redis = RedisPoolOptions::new(fake_redis().await)
+            .max_connections(1)
+            .acquire_timeout(Duration::from_secs(5))
+            .create().await.unwrap();
+
+        let held = redis.try_conn().await.unwrap();
+        {
+            let mut waiting = Box::pin(redis.try_conn());
+            assert!(futures::poll!(&mut waiting).is_pending());
+            assert_eq!(redis.stats().waiters, 1);
+        }
+        assert_eq!(redis.stats().waiters, 0);
+
+        drop(held);
+        assert_eq!(redis.stats().idle, 1);
+        assert!(tokio::time::timeout(Duration::from_millis(100), redis.try_conn()).await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn release_skips_abandoned_waiters() {
+        let redis = RedisPoolOptions::new(fake_redis().await)
+            .max_connections(1)
+            .create().await.unwrap();
+
+        let held = redis.try_conn().await.unwrap();
+        drop(redis.pool.lock().unwrap().acquire());
+        assert_eq!(redis.stats().waiters, 1);
+
+        drop(held);
+        assert_eq!(redis.stats().waiters, 0);
+        assert_eq!(redis.stats().idle, 1);
+    }
+}
@@ -4,26 +4,20 @@ use sqlx::{query_as};
 macro_rules! redis_cached {
     ($ctx:expr, $key: expr, $expiry: expr, $value: expr) => {
         {
-            let mut redis = get_redis_conn($ctx).await;
-
-            match redis.get($key).await {
-                Ok(value) => Ok(value),
-                Err(_) => {
-                    let value = $value;
-                    redis.set_ex($key, value, $expiry).await?;
-                    Ok(value)
-                }
-            }
+            let ctx = $ctx;
+            cached(get_redis(ctx), $key, $expiry, || async move { Ok($value) }).await
         }
     }
 }
 
+//the span is named after the call site, parameter values are never recorded
 //todo create macro, to create macro
 #[macro_export]
 macro_rules! query_count {
     ($ctx:expr, $sql:expr, $($arg:expr),*) => {
         query!($sql, $($arg),*)
             .fetch_one(get_db($ctx))
+            .instrument(crate::telemetry::sql_span(concat!(module_path!(), ":", line!())))
             .await?
             .count.unwrap_or_else(|| 0)
     }
@@ -34,6 +28,7 @@ macro_rules! query_one {
     ($ctx:expr, $sql:literal, $($arg:expr),*) => {
         query!($sql, $($arg),*)
             .fetch_one(get_db($ctx))
+            .instrument(crate::telemetry::sql_span(concat!(module_path!(), ":", line!())))
             .await?
     }
 }
@@ -43,6 +38,7 @@ macro_rules! query_one_as {
     ($ctx:expr, $typ:path, $sql:literal, $($arg:expr),*) => {
         query_as!($typ, $sql, $($arg),*)
             .fetch_one(get_db($ctx))
+            .instrument(crate::telemetry::sql_span(concat!(module_path!(), ":", line!())))
             .await?
     }
 }
@@ -52,6 +48,7 @@ macro_rules! query_all {
     ($ctx:expr, $sql:literal, $($arg:expr),*) => {
         query!($sql, $($arg),*)
             .fetch_all(get_db($ctx))
+            .instrument(crate::telemetry::sql_span(concat!(module_path!(), ":", line!())))
             .await?
     }
 }
@@ -61,6 +58,7 @@ macro_rules! query_all_as {
     ($ctx:expr, $typ:path, $sql:literal, $($arg:expr),*) => {
         query_as!($typ, $sql, $($arg),*)
             .fetch_all(get_db($ctx))
+            .instrument(crate::telemetry::sql_span(concat!(module_path!(), ":", line!())))
             .await?
     }
 }
@@ -79,6 +77,7 @@ macro_rules! select_one_from {
             let row = sqlx::query(&sql)
                 $(.bind($arg))*
                 .fetch_one(get_db($ctx))
+                .instrument(crate::telemetry::sql_span(concat!(module_path!(), ":", line!())))
                 .await?;
             let mut index = 0;
 
@@ -89,17 +88,40 @@ macro_rules! select_one_from {
 
 #[macro_export]
 macro_rules! select_all_from {
+    ($ctx:expr, $typ:path, $sql:literal, page = $page:expr, $($arg:expr),*) => {
+        {
+            let ctx = $ctx;
+            let sql = <($typ)>::sql_for_page(ctx, $sql, &$page);
+            resolve_all_rows!(ctx, $typ, sql, $($arg),*)
+        }
+    };
     ($ctx:expr, $typ:path, $sql:literal, $($arg:expr),*) => {
         {
             let ctx = $ctx;
-            let sql = <($typ)>::sql_for_select($ctx, $sql);
-            let row = sqlx::query(&sql)
+            let sql = <($typ)>::sql_for_select(ctx, $sql);
+            resolve_all_rows!(ctx, $typ, sql, $($arg),*)
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! resolve_all_rows {
+    ($ctx:expr, $typ:path, $sql:expr, $($arg:expr),*) => {
+        {
+            let ctx = $ctx;
+            let rows = sqlx::query(&$sql)
                 $(.bind($arg))*
-                .fetch_all(get_db($ctx))
+                .fetch_all(get_db(ctx))
+                .instrument(crate::telemetry::sql_span(concat!(module_path!(), ":", line!())))
                 .await?;
-            let mut index = 0;
+            let look = ctx.look_ahead();
 
-            <($typ)>::resolve(&row, ctx, &ctx.look_ahead(), &mut index)?
+            let mut results = Vec::with_capacity(rows.len());
+            for row in &rows {
+                let mut index = 0;
+                results.push(<($typ)>::resolve(row, ctx, &look, &mut index)?);
+            }
+            results
         }
     }
-}
\ No newline at end of file
+}
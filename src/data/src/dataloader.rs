@@ -8,6 +8,7 @@ use std::marker::Send;
 use std::mem;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use std::task::{Context, Poll, Waker};
 use std::time::{Duration};
 use std::vec::Vec;
@@ -192,9 +193,14 @@ impl<T: Send> Future for DataLoaderFuture<T> {
 }
 
 #[derive(Clone)]
-pub struct DataLoaderEndpoint<T: Send>(Sender<DataLoaderFuture<T>>);
+pub struct DataLoaderEndpoint<T: Send>(Sender<DataLoaderFuture<T>>, Arc<AtomicBool>);
 
 impl<T: Send> DataLoaderEndpoint<T> {
+    //false once the loader task has exited, e.g. after a panic in the handler
+    pub fn is_running(&self) -> bool {
+        self.1.load(AtomicOrdering::Acquire)
+    }
+
     pub async fn load(&mut self, id: ID) -> Result<T, String> {
         let shared = Arc::new(Mutex::new(DataLoaderFutureShared {
             result: DataResult::Pending,
@@ -249,9 +255,16 @@ pub struct DataLoader<
     batch_size: usize,
     timeout: Duration,
     timeout_t: Delay,
+    running: Arc<AtomicBool>,
     phantom: std::marker::PhantomData<C>,
 }
 
+impl<F: DataLoaderHandler<T, C>, C: Send + Sync, T: Clone + Send> Drop for DataLoader<F, C, T> {
+    fn drop(&mut self) {
+        self.running.store(false, AtomicOrdering::Release);
+    }
+}
+
 impl<
         F: 'static + Send + DataLoaderHandler<T,C>, // Fn(&SharedContext, &mut HashMap<ID, DataResult<T>>) -> Fut,
         C: 'static + Send + Sync,
@@ -271,6 +284,7 @@ impl<
         timeout: Duration,
     ) -> DataLoaderEndpoint<T> {
         let (tx, rx) = channel(batch_size);
+        let running = Arc::new(AtomicBool::new(true));
 
         tokio::spawn(
             DataLoader {
@@ -282,12 +296,13 @@ impl<
                 batch_size: batch_size,
                 timeout: timeout,
                 timeout_t: Self::infinite_t(),
+                running: running.clone(),
                 phantom: std::marker::PhantomData,
             }
             .run(shared_ctx),
         );
 
-        DataLoaderEndpoint(tx)
+        DataLoaderEndpoint(tx, running)
     }
 
     pub async fn run(mut self, ctx: Arc<C>) {
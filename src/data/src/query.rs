@@ -0,0 +1,332 @@
+use crate::sql_resolve::{SQLResolve, SQLTable, MAX_PAGE_LIMIT};
+use async_graphql::{Context, FieldResult};
+use chrono::{DateTime, Utc};
+use sqlx::postgres::{PgArguments, Postgres};
+use sqlx::query::Query;
+use sqlx::Pool;
+use std::marker::PhantomData;
+
+//Columns are always &'static str, only values are user supplied and those are bound as parameters
+#[derive(Clone, Debug)]
+pub enum SQLValue {
+    Int(i32),
+    BigInt(i64),
+    Float(f64),
+    Bool(bool),
+    Text(String),
+    Timestamp(DateTime<Utc>),
+    IntArray(Vec<i32>),
+    TextArray(Vec<String>),
+}
+
+macro_rules! sql_value_from {
+    ($typ: ty, $variant: ident) => {
+        impl From<$typ> for SQLValue {
+            fn from(value: $typ) -> SQLValue { SQLValue::$variant(value) }
+        }
+    }
+}
+
+sql_value_from!(i32, Int);
+sql_value_from!(i64, BigInt);
+sql_value_from!(f64, Float);
+sql_value_from!(bool, Bool);
+sql_value_from!(String, Text);
+sql_value_from!(DateTime<Utc>, Timestamp);
+sql_value_from!(Vec<i32>, IntArray);
+sql_value_from!(Vec<String>, TextArray);
+
+impl From<&str> for SQLValue {
+    fn from(value: &str) -> SQLValue { SQLValue::Text(value.to_string()) }
+}
+
+impl SQLValue {
+    fn bind<'q>(self, query: Query<'q, Postgres, PgArguments>) -> Query<'q, Postgres, PgArguments> {
+        match self {
+            SQLValue::Int(v) => query.bind(v),
+            SQLValue::BigInt(v) => query.bind(v),
+            SQLValue::Float(v) => query.bind(v),
+            SQLValue::Bool(v) => query.bind(v),
+            SQLValue::Text(v) => query.bind(v),
+            SQLValue::Timestamp(v) => query.bind(v),
+            SQLValue::IntArray(v) => query.bind(v),
+            SQLValue::TextArray(v) => query.bind(v),
+        }
+    }
+}
+
+enum Filter {
+    Eq(&'static str),
+    In(&'static str),
+    Contains(&'static str),
+    Like(&'static str),
+    Min(&'static str),
+    Max(&'static str),
+    Search(&'static str),
+    //'{}' is replaced by the parameter of the filter when it has a value. '?' is not used as it
+    //collides with the jsonb operators ?, ?| and ?&
+    Raw(&'static str),
+}
+
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+pub struct Select<T: SQLTable> {
+    filters: Vec<(Filter, Option<SQLValue>)>,
+    order_by: Option<(&'static str, Order)>,
+    cursor: Option<SQLValue>,
+    limit: Option<i64>,
+    phantom: PhantomData<T>,
+}
+
+impl<T: SQLTable> Select<T> {
+    pub fn new() -> Select<T> {
+        Select {
+            filters: Vec::new(),
+            order_by: None,
+            cursor: None,
+            limit: None,
+            phantom: PhantomData,
+        }
+    }
+
+    fn filter<V: Into<SQLValue>>(mut self, filter: Filter, value: Option<V>) -> Self {
+        if let Some(value) = value {
+            self.filters.push((filter, Some(value.into())));
+        }
+        self
+    }
+
+    pub fn eq<V: Into<SQLValue>>(self, column: &'static str, value: V) -> Self {
+        self.filter(Filter::Eq(column), Some(value))
+    }
+
+    //Filters that take an option are skipped when None, so optional GraphQL input fields can be passed through directly
+    pub fn eq_opt<V: Into<SQLValue>>(self, column: &'static str, value: Option<V>) -> Self {
+        self.filter(Filter::Eq(column), value)
+    }
+
+    pub fn any_of<V: Into<SQLValue>>(self, column: &'static str, values: Option<V>) -> Self {
+        self.filter(Filter::In(column), values)
+    }
+
+    //array column contains the value, e.g. sdgs
+    pub fn contains<V: Into<SQLValue>>(self, column: &'static str, value: Option<V>) -> Self {
+        self.filter(Filter::Contains(column), value)
+    }
+
+    pub fn like<V: Into<SQLValue>>(self, column: &'static str, pattern: Option<V>) -> Self {
+        self.filter(Filter::Like(column), pattern)
+    }
+
+    pub fn range<V: Into<SQLValue>>(self, column: &'static str, min: Option<V>, max: Option<V>) -> Self {
+        self.filter(Filter::Min(column), min).filter(Filter::Max(column), max)
+    }
+
+    pub fn search<V: Into<SQLValue>>(self, column: &'static str, query: Option<V>) -> Self {
+        self.filter(Filter::Search(column), query)
+    }
+
+    pub fn where_sql<V: Into<SQLValue>>(mut self, condition: &'static str, value: Option<V>) -> Self {
+        self.filters.push((Filter::Raw(condition), value.map(Into::into)));
+        self
+    }
+
+    pub fn order_by(mut self, column: &'static str, order: Order) -> Self {
+        self.order_by = Some((column, order));
+        self
+    }
+
+    //keyset pagination, only returns rows after the cursor in the order_by column
+    pub fn after<V: Into<SQLValue>>(mut self, cursor: Option<V>) -> Self {
+        self.cursor = cursor.map(Into::into);
+        self
+    }
+
+    pub fn limit(mut self, limit: i64) -> Self {
+        self.limit = Some(limit.max(0).min(MAX_PAGE_LIMIT));
+        self
+    }
+
+    fn where_clause(&self) -> (String, Vec<SQLValue>) {
+        let mut conditions = Vec::with_capacity(self.filters.len() + 1);
+        let mut args = Vec::with_capacity(self.filters.len() + 1);
+
+        for (filter, value) in &self.filters {
+            let param = format!("${}", args.len() + 1);
+            if let Some(value) = value {
+                args.push(value.clone());
+            }
+
+            conditions.push(match filter {
+                Filter::Eq(column) => format!("{}.{} = {}", T::TABLE, column, param),
+                Filter::In(column) => format!("{}.{} = ANY({})", T::TABLE, column, param),
+                Filter::Contains(column) => format!("{} = ANY({}.{})", param, T::TABLE, column),
+                Filter::Like(column) => format!("{}.{} LIKE {}", T::TABLE, column, param),
+                Filter::Min(column) => format!("{}.{} >= {}", T::TABLE, column, param),
+                Filter::Max(column) => format!("{}.{} <= {}", T::TABLE, column, param),
+                //websearch syntax accepts any user input, to_tsquery fails on plain text like "foo bar"
+                Filter::Search(column) => format!("{}.{} @@ websearch_to_tsquery({})", T::TABLE, column, param),
+                Filter::Raw(condition) if value.is_some() => condition.replace("{}", &param),
+                Filter::Raw(condition) => condition.to_string(),
+            });
+        }
+
+        if let (Some((column, order)), Some(cursor)) = (&self.order_by, &self.cursor) {
+            let op = match order { Order::Asc => ">", Order::Desc => "<" };
+            args.push(cursor.clone());
+            conditions.push(format!("{}.{} {} ${}", T::TABLE, column, op, args.len()));
+        }
+
+        let mut sql = String::new();
+        if conditions.len() > 0 {
+            sql += "WHERE ";
+            sql += &conditions.join(" AND ");
+        }
+
+        if let Some((column, order)) = &self.order_by {
+            let order = match order { Order::Asc => "ASC", Order::Desc => "DESC" };
+            sql += &format!(" ORDER BY {}.{} {}", T::TABLE, column, order);
+        }
+
+        if let Some(limit) = self.limit {
+            sql += &format!(" LIMIT {}", limit);
+        }
+
+        (sql, args)
+    }
+
+    pub async fn fetch_all(self, ctx: &Context<'_>, db: &Pool<Postgres>) -> FieldResult<Vec<T::T>> {
+        let (where_clause, args) = self.where_clause();
+        let sql = T::sql_for_select(ctx, &where_clause);
+
+        let mut query = sqlx::query(&sql);
+        for arg in args {
+            query = arg.bind(query);
+        }
+
+        let rows = query.fetch_all(db).await?;
+        let look = ctx.look_ahead();
+
+        let mut results = Vec::with_capacity(rows.len());
+        for row in &rows {
+            let mut index = 0;
+            results.push(T::resolve(row, ctx, &look, &mut index)?);
+        }
+
+        Ok(results)
+    }
+
+    pub async fn fetch_one(self, ctx: &Context<'_>, db: &Pool<Postgres>) -> FieldResult<T::T> {
+        let (where_clause, args) = self.where_clause();
+        let sql = T::sql_for_select(ctx, &where_clause);
+
+        let mut query = sqlx::query(&sql);
+        for arg in args {
+            query = arg.bind(query);
+        }
+
+        let row = query.fetch_one(db).await?;
+        let mut index = 0;
+
+        T::resolve(&row, ctx, &ctx.look_ahead(), &mut index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_resolve::{Field, Join};
+    use async_graphql::Lookahead;
+    use sqlx::postgres::PgRow;
+
+    struct Things;
+
+    impl SQLResolve for Things {
+        type T = ();
+        fn resolve(_row: &PgRow, _ctx: &Context<'_>, _look: &Lookahead<'_>, _index: &mut usize) -> FieldResult<()> { Ok(()) }
+        fn require_field(_fields: &mut Vec<Field>, _joins: &mut Vec<Join>, _lookahead: &Lookahead<'_>, _field: &Field) {}
+    }
+
+    impl SQLTable for Things {
+        const TABLE: &'static str = "Things";
+    }
+
+    fn sql(select: Select<Things>) -> (String, usize) {
+        let (sql, args) = select.where_clause();
+        (sql, args.len())
+    }
+
+    #[test]
+    fn empty_select_has_no_clause() {
+        assert_eq!(sql(Select::new()), ("".to_string(), 0));
+    }
+
+    #[test]
+    fn filters_are_numbered_in_order() {
+        let select = Select::<Things>::new()
+            .eq("a", 1)
+            .any_of("b", Some(vec![1, 2]))
+            .contains("c", Some(3))
+            .like("d", Some("x%"))
+            .range("e", Some(1), Some(5))
+            .search("f", Some("foo bar"));
+
+        assert_eq!(sql(select), (
+            "WHERE Things.a = $1 AND Things.b = ANY($2) AND $3 = ANY(Things.c) AND Things.d LIKE $4 \
+            AND Things.e >= $5 AND Things.e <= $6 AND Things.f @@ websearch_to_tsquery($7)".to_string(),
+            7,
+        ));
+    }
+
+    #[test]
+    fn missing_optional_filters_are_skipped() {
+        let select = Select::<Things>::new()
+            .eq_opt("a", None::<i32>)
+            .range("b", None, Some(2))
+            .eq("c", 3);
+
+        assert_eq!(sql(select), ("WHERE Things.b <= $1 AND Things.c = $2".to_string(), 2));
+    }
+
+    #[test]
+    fn raw_conditions_only_take_a_placeholder_with_a_value() {
+        let select = Select::<Things>::new()
+            .where_sql("Things.hidden", None::<i32>)
+            .where_sql("(Things.a = {} OR Things.b = {})", Some(1))
+            .eq("c", 2);
+
+        assert_eq!(sql(select), ("WHERE Things.hidden AND (Things.a = $1 OR Things.b = $1) AND Things.c = $2".to_string(), 2));
+    }
+
+    #[test]
+    fn raw_conditions_keep_jsonb_operators() {
+        let select = Select::<Things>::new()
+            .where_sql("Things.tags ? 'a' AND Things.tags ?| array['b']", None::<i32>)
+            .where_sql("Things.tags ?& {}", Some(vec!["c".to_string()]));
+
+        assert_eq!(sql(select), ("WHERE Things.tags ? 'a' AND Things.tags ?| array['b'] AND Things.tags ?& $1".to_string(), 1));
+    }
+
+    #[test]
+    fn cursor_follows_filters_and_order() {
+        let asc = Select::<Things>::new().eq("a", 1).order_by("id", Order::Asc).after(Some(10)).limit(5);
+        assert_eq!(sql(asc), ("WHERE Things.a = $1 AND Things.id > $2 ORDER BY Things.id ASC LIMIT 5".to_string(), 2));
+
+        let desc = Select::<Things>::new().order_by("id", Order::Desc).after(Some(10));
+        assert_eq!(sql(desc), ("WHERE Things.id < $1 ORDER BY Things.id DESC".to_string(), 1));
+    }
+
+    #[test]
+    fn cursor_without_order_is_ignored() {
+        assert_eq!(sql(Select::<Things>::new().after(Some(10))), ("".to_string(), 0));
+    }
+
+    #[test]
+    fn limit_is_clamped() {
+        assert_eq!(sql(Select::<Things>::new().limit(-1)).0, " LIMIT 0");
+        assert_eq!(sql(Select::<Things>::new().limit(i64::MAX)).0, format!(" LIMIT {}", MAX_PAGE_LIMIT));
+    }
+}
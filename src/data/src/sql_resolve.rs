@@ -1,6 +1,7 @@
-use async_graphql::{Context, FieldResult, Lookahead};
-use sqlx::postgres::PgRow;
-use sqlx::Row;
+use async_graphql::{Context, FieldError, FieldResult, Lookahead};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use sqlx::postgres::{PgRow, Postgres};
+use sqlx::{Column, Decode, Row, Type};
 
 pub trait SQLResolve {
     type T;
@@ -14,6 +15,30 @@ pub trait SQLTable : SQLResolve {
     const TABLE : &'static str;
 
     fn sql_for_select(ctx: &Context<'_>, where_clause: &str) -> String {
+        let (fields, joins) = Self::required_fields(ctx);
+        build_select(Self::TABLE, &fields, &joins, where_clause)
+    }
+
+    fn sql_for_page(ctx: &Context<'_>, where_clause: &str, page: &Page) -> String {
+        let (fields, joins) = Self::required_fields(ctx);
+        let mut sql = build_select(Self::TABLE, &fields, &joins, where_clause);
+        if sql.len() == 0 {
+            return sql;
+        }
+
+        //cursor and limit are integers, so they can be formatted directly into the query
+        if let Some(cursor) = page.cursor {
+            sql += if where_clause.trim().is_empty() { " WHERE " } else { " AND " };
+            sql += &format!("{}.{} {} {}", Self::TABLE, page.order_by, if page.desc { "<" } else { ">" }, cursor);
+        }
+
+        sql += &format!(" ORDER BY {}.{} {}", Self::TABLE, page.order_by, if page.desc { "DESC" } else { "ASC" });
+        sql += &format!(" LIMIT {}", page.limit);
+
+        sql
+    }
+
+    fn required_fields(ctx: &Context<'_>) -> (Vec<Field>, Vec<Join>) {
         let mut fields = Vec::new();
         let mut joins = Vec::new();
         Self::require_field(&mut fields, &mut joins, &ctx.look_ahead(), &Field {
@@ -22,44 +47,71 @@ pub trait SQLTable : SQLResolve {
             field: "",
         });
 
-        if fields.len() == 0 {
-            return "".to_string();
-        }
+        (fields, joins)
+    }
+}
 
-        let mut sql = "SELECT ".to_string();
-        for (i, field) in fields.iter().enumerate() {
-            sql += field.table;
-            sql += ".";
-            sql += field.field;
-            sql += " as ";
-            sql += &field.name;
-            if i + 1 < fields.len() {
-                sql += ", ";
-            }
-        }
+fn build_select(table: &'static str, fields: &[Field], joins: &[Join], where_clause: &str) -> String {
+    if fields.len() == 0 {
+        return "".to_string();
+    }
 
-        sql += " FROM ";
-        sql += joins[0].table;
-
-        for (i, join) in joins[1..].iter().enumerate() {
-            sql += " INNER JOIN ";
-            sql += join.table;
-            sql += "ON ";
-            sql += join.table;
-            sql += ".id = ";
-            sql += joins[i - 1].table;
-            sql += ".";
-            sql += join.on;
-            sql += "\n";
-            joins[i - 1].table;
+    let mut sql = "SELECT ".to_string();
+    for (i, field) in fields.iter().enumerate() {
+        sql += field.table;
+        sql += ".";
+        sql += field.field;
+        sql += " as ";
+        sql += &field.name;
+        if i + 1 < fields.len() {
+            sql += ", ";
         }
+    }
 
-        sql += " ";
-        sql += where_clause;
+    sql += " FROM ";
+    sql += joins.first().map_or(table, |join| join.table);
 
-        println!("Generated sql {}", sql);
+    for (i, join) in joins.iter().enumerate().skip(1) {
+        //left joins only for Option<S>, so a null foreign key resolves to None instead of dropping the row
+        sql += if join.optional { " LEFT JOIN " } else { " INNER JOIN " };
+        sql += join.table;
+        sql += " ON ";
+        sql += join.table;
+        sql += ".id = ";
+        sql += joins[i - 1].table;
+        sql += ".";
+        sql += join.on;
+        sql += "\n";
+    }
+
+    sql += " ";
+    sql += where_clause;
+
+    sql
+}
+
+/// Keyset pagination for list queries, rows are ordered by `order_by` and only
+/// rows past `cursor` are returned.
+#[derive(Clone)]
+pub struct Page {
+    pub order_by: &'static str,
+    pub desc: bool,
+    pub cursor: Option<i64>,
+    pub limit: i64,
+}
+
+/// Upper bound for the limit of every page, larger requests are clamped.
+pub const MAX_PAGE_LIMIT: i64 = 100;
 
-        return sql;
+impl Page {
+    //the limit is formatted into the query, so it is clamped to a valid range here
+    pub fn new(order_by: &'static str, cursor: Option<i64>, limit: i64) -> Page {
+        Page { order_by, desc: false, cursor, limit: limit.max(0).min(MAX_PAGE_LIMIT) }
+    }
+
+    pub fn desc(mut self) -> Self {
+        self.desc = true;
+        self
     }
 }
 
@@ -74,24 +126,117 @@ pub struct Field {
 pub struct Join {
     pub table: &'static str,
     pub on: &'static str,
+    pub optional: bool,
 }
 
+impl Join {
+    //required relation, rows without a match are not returned
+    pub fn new(table: &'static str, on: &'static str) -> Join {
+        Join { table, on, optional: false }
+    }
+}
 
-macro_rules! sql_resolve_for_prim {
-    ($typ: path) => {
+
+pub fn decode_column<'r, T: Decode<'r, Postgres> + Type<Postgres>>(row: &'r PgRow, index: usize) -> FieldResult<T> {
+    row.try_get(index).map_err(|e| {
+        let column = row.columns().get(index).map_or("<unknown>", |column| column.name());
+        FieldError(format!("Could not decode column {}: {}", column, e), None)
+    })
+}
+
+macro_rules! sql_resolve_for_column {
+    ($typ: ty) => {
         impl SQLResolve for $typ {
             type T = $typ;
             fn resolve(row: &PgRow, ctx: &Context<'_>, look: &Lookahead<'_>, index: &mut usize) -> FieldResult<Self::T> {
                 *index += 1;
-                Ok(row.try_get(*index - 1)?)
+                decode_column(row, *index - 1)
             }
         }
     }
 }
 
+//nullable columns and postgres arrays of the same type
+macro_rules! sql_resolve_for_prim {
+    ($typ: ty) => {
+        sql_resolve_for_column!($typ);
+        sql_resolve_for_column!(Option<$typ>);
+        sql_resolve_for_column!(Vec<$typ>);
+        sql_resolve_for_column!(Option<Vec<$typ>>);
+    }
+}
+
 sql_resolve_for_prim!(String);
 //sql_resolve_for_prim!(str);
 sql_resolve_for_prim!(i32);
-sql_resolve_for_prim!(u32);
+sql_resolve_for_column!(u32);
+sql_resolve_for_column!(Option<u32>);
+sql_resolve_for_prim!(i64);
 sql_resolve_for_prim!(f32);
 sql_resolve_for_prim!(f64);
+sql_resolve_for_prim!(bool);
+sql_resolve_for_prim!(DateTime<Utc>);
+sql_resolve_for_prim!(NaiveDateTime);
+sql_resolve_for_prim!(NaiveDate);
+
+//nested table behind a nullable foreign key, the id of the joined table is always selected first
+//so a missing row can be told apart from columns that are null
+impl<S: SQLTable> SQLResolve for Option<S> {
+    type T = Option<S::T>;
+
+    fn resolve(row: &PgRow, ctx: &Context<'_>, look: &Lookahead<'_>, index: &mut usize) -> FieldResult<Self::T> {
+        let id: Option<i32> = decode_column(row, *index)?;
+        *index += 1;
+
+        if id.is_some() {
+            return Ok(Some(S::resolve(row, ctx, look, index)?));
+        }
+
+        let mut fields = Vec::new();
+        let mut joins = Vec::new();
+        S::require_field(&mut fields, &mut joins, look, &Field {
+            table: S::TABLE,
+            name: "".to_string(),
+            field: "",
+        });
+
+        *index += fields.len();
+        Ok(None)
+    }
+
+    fn require_field(fields: &mut Vec<Field>, joins: &mut Vec<Join>, lookahead: &Lookahead<'_>, field: &Field) {
+        fields.push(Field {
+            table: S::TABLE,
+            name: format!("{}__id", field.name),
+            field: "id",
+        });
+
+        //tables nested below an optional one are optional as well, a missing parent has no children
+        let first = joins.len();
+        S::require_field(fields, joins, lookahead, field);
+        for join in &mut joins[first..] {
+            join.optional = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(table: &'static str, name: &str) -> Field {
+        Field { table, name: name.to_string(), field: "id" }
+    }
+
+    #[test]
+    fn only_optional_relations_are_left_joined() {
+        let mut project = Join::new("Projects", "project");
+        project.optional = true;
+        let joins = [Join::new("Posts", ""), Join::new("Users", "account"), project];
+        let fields = [field("Posts", "id"), field("Users", "account__id"), field("Projects", "project__id")];
+
+        let sql = build_select("Posts", &fields, &joins, "WHERE Posts.id = $1");
+        assert!(sql.contains(" INNER JOIN Users ON Users.id = Posts.account"));
+        assert!(sql.contains(" LEFT JOIN Projects ON Projects.id = Users.project"));
+    }
+}
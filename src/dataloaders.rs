@@ -1,22 +1,123 @@
+use tracing::Instrument;
+use crate::telemetry::sql_span;
 use crate::context::{SharedContext};
 use data::dataloader::*;
 use crate::schema::*;
-use crate::prof::*;
+use crate::image::ImageInfo;
 use log::info;
 use async_trait::async_trait;
 use std::collections::HashMap;
 use std::error::Error;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration};
-use sqlx::{query_as};
+use sqlx::{query, query_as};
 use serde::export::Formatter;
 
 pub struct Loaders {
     pub account: DataLoaderEndpoint<Account>,
+    pub image: DataLoaderEndpoint<ImageInfo>,
+    pub like_count: DataLoaderEndpoint<i64>,
+}
+
+impl Loaders {
+    pub fn running(&self) -> bool {
+        self.account.is_running() && self.image.is_running() && self.like_count.is_running()
+    }
 }
 
 struct AccountLoader {}
 
+struct ImageLoader {}
+
+#[async_trait]
+impl DataLoaderHandler<ImageInfo, SharedContext> for ImageLoader {
+    async fn batch_execute(
+        &mut self,
+        shared: &SharedContext,
+        results: &mut HashMap<ID, DataResult<ImageInfo>>,
+    ) -> Result<(), data::dataloader::Error> {
+        let ids: Vec<ID> = results.keys().copied().collect();
+
+        let images = query_as!(ImageInfo, "SELECT id, width, height, contenttype, blurhash, status FROM Images WHERE id = ANY($1)", &ids)
+            .fetch_all(&shared.db)
+            .instrument(sql_span("dataloaders.images"))
+            .await?;
+
+        for image in images {
+            results.insert(image.id, DataResult::Ok(image));
+        }
+
+        for (id, result) in results.iter_mut() {
+            if let DataResult::Pending = result {
+                *result = DataResult::Error(format!("Could not find image {}", id));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+//keyed by post, so a page of posts costs a single count query
+struct LikeCountLoader {}
+
+#[async_trait]
+impl DataLoaderHandler<i64, SharedContext> for LikeCountLoader {
+    async fn batch_execute(
+        &mut self,
+        shared: &SharedContext,
+        results: &mut HashMap<ID, DataResult<i64>>,
+    ) -> Result<(), data::dataloader::Error> {
+        let ids: Vec<ID> = results.keys().copied().collect();
+
+        let counts = query!("SELECT post, COUNT(*) as count FROM PostLikes WHERE post = ANY($1) GROUP BY post", &ids)
+            .fetch_all(&shared.db)
+            .instrument(sql_span("dataloaders.like_counts"))
+            .await?;
+
+        //posts without likes have no row
+        for result in results.values_mut() {
+            *result = DataResult::Ok(0);
+        }
+
+        for count in counts {
+            results.insert(count.post, DataResult::Ok(count.count.unwrap_or(0)));
+        }
+
+        Ok(())
+    }
+}
+
+//keyed by post, the viewer is fixed when the loader is created, so every authenticated request gets its own
+struct LikedByMeLoader {
+    viewer: ID,
+}
+
+#[async_trait]
+impl DataLoaderHandler<bool, SharedContext> for LikedByMeLoader {
+    async fn batch_execute(
+        &mut self,
+        shared: &SharedContext,
+        results: &mut HashMap<ID, DataResult<bool>>,
+    ) -> Result<(), data::dataloader::Error> {
+        let ids: Vec<ID> = results.keys().copied().collect();
+
+        let liked = query!("SELECT post FROM PostLikes WHERE post = ANY($1) AND account = $2", &ids, self.viewer)
+            .fetch_all(&shared.db)
+            .instrument(sql_span("dataloaders.liked_by_me"))
+            .await?;
+
+        for result in results.values_mut() {
+            *result = DataResult::Ok(false);
+        }
+
+        for like in liked {
+            results.insert(like.post, DataResult::Ok(true));
+        }
+
+        Ok(())
+    }
+}
+
 struct DidNotFindPostError { id: i32 }
 
 impl std::fmt::Display for DidNotFindPostError {
@@ -83,10 +184,37 @@ impl DataLoaderHandler<Account, SharedContext> for AccountLoader {
 
 pub fn make_loaders(shared: Arc<SharedContext>) -> Arc<Loaders> {
     Arc::new(Loaders {
-        account: DataLoader::new(AccountLoader {}, shared, 10, Duration::from_millis(10)),
+        account: DataLoader::new(AccountLoader {}, shared.clone(), 10, Duration::from_millis(10)),
+        image: DataLoader::new(ImageLoader {}, shared.clone(), 10, Duration::from_millis(10)),
+        like_count: DataLoader::new(LikeCountLoader {}, shared, 10, Duration::from_millis(10)),
     })
 }
 
 pub fn get_loaders<'a>(ctx: &'a async_graphql::Context<'_>) -> &'a Loaders {
     &ctx.data::<Arc<Loaders>>().unwrap()
 }
+
+//loaders whose results depend on who is asking. They are created on first use, so operations that never
+//select a viewer dependent field do not spawn a loader task. The task exits once the request is done
+pub struct ViewerLoaders {
+    shared: Arc<SharedContext>,
+    viewer: ID,
+    liked_by_me: Mutex<Option<DataLoaderEndpoint<bool>>>,
+}
+
+impl ViewerLoaders {
+    pub fn liked_by_me(&self) -> DataLoaderEndpoint<bool> {
+        let mut liked_by_me = self.liked_by_me.lock().unwrap();
+        liked_by_me.get_or_insert_with(|| DataLoader::new(LikedByMeLoader { viewer: self.viewer }, self.shared.clone(), 10, Duration::from_millis(10)))
+            .clone()
+    }
+}
+
+pub fn make_viewer_loaders(shared: Arc<SharedContext>, viewer: ID) -> ViewerLoaders {
+    ViewerLoaders { shared, viewer, liked_by_me: Mutex::new(None) }
+}
+
+//None for anonymous viewers
+pub fn get_viewer_loaders<'a>(ctx: &'a async_graphql::Context<'_>) -> Option<&'a ViewerLoaders> {
+    ctx.data::<ViewerLoaders>().ok()
+}
@@ -0,0 +1,196 @@
+use async_graphql::{Context, FieldError, FieldResult, QueryError};
+use hyper::http::status::StatusCode;
+use serde::Serialize;
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use tracing::error;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    Unauthenticated,
+    Forbidden,
+    NotFound,
+    Validation,
+    Internal,
+    PersistedQueryNotFound,
+    PersistedQueryNotAllowed,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Unauthenticated => "UNAUTHENTICATED",
+            ErrorCode::Forbidden => "FORBIDDEN",
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::Validation => "VALIDATION",
+            ErrorCode::Internal => "INTERNAL",
+            ErrorCode::PersistedQueryNotFound => "PERSISTED_QUERY_NOT_FOUND",
+            ErrorCode::PersistedQueryNotAllowed => "PERSISTED_QUERY_NOT_ALLOWED",
+        }
+    }
+}
+
+pub fn field_error(code: ErrorCode, message: &str) -> FieldError {
+    FieldError(message.to_string(), Some(json!({ "code": code.as_str() })))
+}
+
+pub fn unauthenticated(message: &str) -> FieldError { field_error(ErrorCode::Unauthenticated, message) }
+pub fn forbidden(message: &str) -> FieldError { field_error(ErrorCode::Forbidden, message) }
+pub fn not_found(message: &str) -> FieldError { field_error(ErrorCode::NotFound, message) }
+pub fn validation(message: &str) -> FieldError { field_error(ErrorCode::Validation, message) }
+
+#[derive(Serialize)]
+pub struct Location {
+    line: usize,
+    column: usize,
+}
+
+#[derive(Serialize)]
+pub struct GraphQLError {
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    locations: Vec<Location>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<serde_json::Value>,
+    extensions: serde_json::Value,
+}
+
+impl GraphQLError {
+    pub fn request(message: String) -> GraphQLError {
+        request_error(message, vec![])
+    }
+
+    pub fn with_code(message: &str, code: ErrorCode) -> GraphQLError {
+        GraphQLError {
+            message: message.to_string(),
+            locations: vec![],
+            path: None,
+            extensions: json!({ "code": code.as_str() }),
+        }
+    }
+}
+
+fn request_error(message: String, locations: Vec<Location>) -> GraphQLError {
+    GraphQLError {
+        message,
+        locations,
+        path: None,
+        extensions: json!({ "code": ErrorCode::Validation.as_str() }),
+    }
+}
+
+//errors without a code come from `?` on database or redis errors, their details are only logged
+fn resolver_error(message: &str, extensions: &Option<serde_json::Value>, location: Location, path: Option<serde_json::Value>) -> GraphQLError {
+    let has_code = extensions.as_ref().map_or(false, |extensions| extensions.get("code").is_some());
+
+    if has_code {
+        return GraphQLError {
+            message: message.to_string(),
+            locations: vec![location],
+            path,
+            extensions: extensions.clone().unwrap(),
+        };
+    }
+
+    error!(error = %message, path = ?path, "Internal error in resolver");
+
+    GraphQLError {
+        message: "Internal server error".to_string(),
+        locations: vec![location],
+        path,
+        extensions: json!({ "code": ErrorCode::Internal.as_str() }),
+    }
+}
+
+//async-graphql stops at the first resolver error, so nullable fields record their errors here and
+//resolve to null instead, the errors are returned next to the partial data
+#[derive(Default)]
+pub struct FieldErrors(Mutex<Vec<GraphQLError>>);
+
+impl FieldErrors {
+    pub fn take(&self) -> Vec<GraphQLError> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+//errors of non null fields still fail the whole request
+pub fn nullable<T>(ctx: &Context<'_>, result: FieldResult<Option<T>>) -> Option<T> {
+    match result {
+        Ok(value) => value,
+        Err(FieldError(message, extensions)) => {
+            let pos = ctx.position();
+            let path = ctx.path_node.as_ref().map(|node| node.to_json());
+            let error = resolver_error(&message, &extensions, Location { line: pos.line, column: pos.column }, path);
+
+            if let Ok(errors) = ctx.data::<Arc<FieldErrors>>() {
+                errors.0.lock().unwrap().push(error);
+            }
+            None
+        }
+    }
+}
+
+//parse and validation errors reject the whole request, resolver errors are reported alongside the data
+pub fn to_graphql_errors(e: &async_graphql::Error) -> (StatusCode, Vec<GraphQLError>) {
+    match e {
+        async_graphql::Error::Parse(e) => (StatusCode::BAD_REQUEST, vec![request_error(e.to_string(), vec![])]),
+        async_graphql::Error::Rule { errors } => (
+            StatusCode::BAD_REQUEST,
+            errors.iter().map(|e| request_error(
+                e.message.clone(),
+                e.locations.iter().map(|pos| Location { line: pos.line, column: pos.column }).collect(),
+            )).collect(),
+        ),
+        async_graphql::Error::Query { pos, path, err } => {
+            let location = Location { line: pos.line, column: pos.column };
+
+            let error = match err {
+                QueryError::FieldError { err, extended_error } => resolver_error(err, extended_error, location, path.clone()),
+                err => GraphQLError {
+                    message: err.to_string(),
+                    locations: vec![location],
+                    path: path.clone(),
+                    extensions: json!({ "code": ErrorCode::Validation.as_str() }),
+                },
+            };
+
+            (StatusCode::OK, vec![error])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_graphql::{EmptyMutation, EmptySubscription, QueryBuilder, Schema};
+    use async_graphql_derive::Object;
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        async fn ok(&self) -> i32 { 1 }
+        async fn failed(&self, ctx: &Context<'_>) -> Option<i32> { nullable(ctx, Err(not_found("Post not found"))) }
+        async fn internal(&self, ctx: &Context<'_>) -> Option<i32> { nullable(ctx, Err("connection reset".into())) }
+    }
+
+    #[tokio::test]
+    async fn nullable_errors_are_returned_next_to_partial_data() {
+        let schema = Schema::new(Query, EmptyMutation, EmptySubscription);
+        let field_errors = Arc::new(FieldErrors::default());
+
+        let response = QueryBuilder::new("{ ok failed internal }")
+            .data(field_errors.clone())
+            .execute(&schema)
+            .await
+            .unwrap();
+
+        assert_eq!(response.data, json!({ "ok": 1, "failed": null, "internal": null }));
+
+        let errors = serde_json::to_value(field_errors.take()).unwrap();
+        assert_eq!(errors[0]["path"], json!(["failed"]));
+        assert_eq!(errors[0]["extensions"]["code"], "NOT_FOUND");
+        assert_eq!(errors[1]["message"], "Internal server error");
+        assert_eq!(errors[1]["extensions"]["code"], "INTERNAL");
+    }
+}
@@ -1,9 +1,14 @@
+use tracing::Instrument;
+use crate::telemetry::sql_span;
 use crate::context::{get_db};
 use crate::auth::{get_auth};
-use crate::prof::Prof;
 use data::dataloader::{ID};
 use data::data_macros::*;
-use crate::schema::{Project, Bond, Post, Image};
+use data::query::{Select, Order};
+use data::sql_resolve::{SQLResolve, SQLTable, MAX_PAGE_LIMIT};
+use crate::schema::{Project, Bond, Post, ImageID};
+use crate::image::Image;
+use crate::errors;
 use async_graphql_derive::*;
 use async_graphql::{Context, FieldResult};
 use sqlx::{query, query_as};
@@ -29,7 +34,7 @@ pub struct Category {
     id: ID,
     title: String,
     description: String,
-    image: Image,
+    image: ImageID,
 }
 
 #[Object]
@@ -37,7 +42,8 @@ impl Category {
     async fn id(&self) -> ID { self.id }
     async fn title(&self) -> &str { &self.title }
     async fn description(&self) -> &str { &self.description }
-    async fn image(&self) -> Image { self.image }
+    async fn image(&self) -> Image { Image::new(self.image) }
+    async fn image_id(&self) -> ImageID { self.image }
 
     async fn trending(&self, ctx: &Context<'_>, cursor: i32, limit: i32) -> FieldResult<Vec<Content>> {
         Ok(vec![])
@@ -49,6 +55,51 @@ impl Category {
 }
 
 
+//bond only filters for search, projects are not matched when one is set
+#[InputObject]
+#[derive(Default)]
+pub struct BondFilter {
+    min_interest: Option<f64>,
+    max_interest: Option<f64>,
+    min_price: Option<i32>,
+    max_price: Option<i32>,
+    max_maturity: Option<i32>,
+}
+
+impl BondFilter {
+    fn is_empty(&self) -> bool {
+        self.min_interest.is_none() && self.max_interest.is_none()
+            && self.min_price.is_none() && self.max_price.is_none()
+            && self.max_maturity.is_none()
+    }
+}
+
+async fn load_project(ctx: &Context<'_>, id: ID) -> FieldResult<Option<Project>> {
+    let result = query_as!(Project, "SELECT id, name, description, image, sdgs, latitude, longitude
+    FROM Projects WHERE id = $1", id)
+        .fetch_optional(get_db(ctx))
+        .instrument(sql_span("explore.project_by_id"))
+        .await?;
+
+    Ok(result)
+}
+
+async fn load_bond(ctx: &Context<'_>, id: ID) -> FieldResult<Option<Bond>> {
+    let result = query_as!(Bond, "SELECT id, image, sdgs, title, issuer, description,
+    interest, maturity, price,
+    msciesrating, moodysrating, standardsandpoor,
+    fitchrating, amountinvested,
+    total, cicerorating
+    FROM BONDS
+    WHERE
+        id = $1", id)
+        .fetch_optional(get_db(ctx))
+        .instrument(sql_span("explore.bond_by_id"))
+        .await?;
+
+    Ok(result)
+}
+
 fn append_content<F: Fn(T) -> Content, T>(vec: &mut Vec<Content>, f: F, content: Vec<T>) {
     vec.reserve(vec.len() + content.len());
     for elem in content {
@@ -71,55 +122,44 @@ impl QueryExplore {
         Ok(vec![])
     }
 
-    async fn project_by_id(&self, ctx: &Context<'_>, id: ID) -> FieldResult<Project> {
-        Ok(query_one_as!(ctx, Project, "SELECT id, name, description, image, sdgs, latitude, longitude
-        FROM Projects WHERE id = $1", id))
+    //null when there is no project with the id, or loading it failed in which case the error is returned next to the data
+    async fn project_by_id(&self, ctx: &Context<'_>, id: ID) -> Option<Project> {
+        errors::nullable(ctx, load_project(ctx, id).await)
     }
 
-    async fn bond_by_id(&self, ctx: &Context<'_>, id: ID) -> FieldResult<Bond> {
-        let result = query_as!(Bond, "SELECT id, image, sdgs, title, issuer, description,
-        interest, maturity, price,
-        msciesrating, moodysrating, standardsandpoor,
-        fitchrating, amountinvested,
-        total, cicerorating
-        FROM BONDS
-        WHERE
-            id = $1", id)
-            .fetch_one(get_db(ctx))
-            .await?;
-
-        Ok(result)
+    async fn bond_by_id(&self, ctx: &Context<'_>, id: ID) -> Option<Bond> {
+        errors::nullable(ctx, load_bond(ctx, id).await)
     }
 
-    async fn search(&self, ctx: &Context<'_>, sdg: Option<i32>, filter: Option<String>) -> FieldResult<Vec<Content>> {
+    async fn search(&self, ctx: &Context<'_>, sdg: Option<i32>, filter: Option<String>, bonds: Option<BondFilter>) -> FieldResult<Vec<Content>> {
         let db = get_db(ctx);
-
-        let mut prof = Prof::new();
-
-        let bonds = query_as!(Bond, "SELECT id, image, sdgs, title, issuer, description,
-        interest, maturity, price,
-        msciesrating, moodysrating, standardsandpoor,
-        fitchrating, amountinvested,
-        total, cicerorating
-        FROM BONDS
-        WHERE
-            ($1::text is null or indexed @@ to_tsquery($1))
-            AND ($2::int is null or $2 = ANY(sdgs))", filter, sdg)
-            .fetch_all(db)
-            .await?;
-
-        prof.log("Searched through bonds");
-
-        let projects = query_as!(Project, "SELECT id, name, description, image, sdgs, latitude, longitude \
-        FROM Projects
-        WHERE
-            (indexed @@ to_tsquery($1) or $1 is null)
-            AND ($2 = ANY(sdgs) or $2 is null)
-            ", filter, sdg)
-            .fetch_all(db)
+        let bond_filter = bonds.unwrap_or_default();
+        let bonds_only = !bond_filter.is_empty();
+
+        let bonds = Select::<Bond>::new()
+            .search("indexed", filter.clone())
+            .contains("sdgs", sdg)
+            .range("interest", bond_filter.min_interest, bond_filter.max_interest)
+            .range("price", bond_filter.min_price, bond_filter.max_price)
+            .range("maturity", None, bond_filter.max_maturity)
+            .order_by("id", Order::Asc)
+            .limit(MAX_PAGE_LIMIT)
+            .fetch_all(ctx, db)
+            .instrument(sql_span("explore.search"))
             .await?;
 
-        prof.log("Searched through projects");
+        let projects = if bonds_only {
+            Vec::new()
+        } else {
+            Select::<Project>::new()
+                .search("indexed", filter)
+                .contains("sdgs", sdg)
+                .order_by("id", Order::Asc)
+                .limit(MAX_PAGE_LIMIT)
+                .fetch_all(ctx, db)
+                .instrument(sql_span("explore.search"))
+                .await?
+        };
 
         let mut result : Vec<Content> = Vec::with_capacity(bonds.len() + projects.len());
         append_content(&mut result, Content::Project, projects);
@@ -0,0 +1,76 @@
+use tracing::Instrument;
+use crate::telemetry::sql_span;
+use crate::context::DBClient;
+use crate::schema::Post;
+use async_graphql::FieldResult;
+use data::dataloader::ID;
+use sqlx::query_as;
+
+pub const MAX_FEED_LIMIT: i64 = 50;
+
+//how much a post is boosted for each reason it is relevant to the viewer
+const FOLLOWED_WEIGHT: f64 = 3.0;
+const MEMBER_WEIGHT: f64 = 2.0;
+const SDG_WEIGHT: f64 = 0.5;
+//score / (hours + 2) ^ GRAVITY, higher values make older posts sink faster
+const GRAVITY: f64 = 1.5;
+//only recent posts and posts from followed accounts or the viewer's projects are ranked,
+//and at most MAX_CANDIDATES of them, so the ranking never scans the whole Posts table
+const CANDIDATE_WINDOW_DAYS: i32 = 14;
+const MAX_CANDIDATES: i64 = 1000;
+
+//SDG interests are taken from projects the viewer is a member of or has liked or commented on posts in,
+//the cursor is an offset since the ranking changes as posts age. Posts without a posted time rank as the oldest,
+//posts without a project are still ranked but get neither the member nor the SDG boost
+pub async fn ranked_feed(db: &DBClient, user: ID, offset: i64, limit: i64) -> FieldResult<Vec<Post>> {
+    let posts = query_as!(Post, r#"WITH
+    followed AS (SELECT following AS account FROM Relationships WHERE follower = $1),
+    member_of AS (SELECT project FROM ProjectMembers WHERE account = $1),
+    engaged AS (
+        SELECT project FROM member_of
+        UNION SELECT Posts.project FROM PostLikes INNER JOIN Posts ON Posts.id = PostLikes.post WHERE PostLikes.account = $1
+        UNION SELECT Posts.project FROM Comments INNER JOIN Posts ON Posts.id = Comments.post WHERE Comments.account = $1
+    ),
+    interests AS (
+        SELECT DISTINCT unnest(sdgs) AS sdg FROM Projects WHERE id IN (SELECT project FROM engaged)
+    ),
+    candidates AS (
+        SELECT Posts.id, Posts.account, Posts.project, Posts.image, Posts.title, Posts.description,
+            COALESCE(Posts.posted, 'epoch'::timestamptz) AS posted
+        FROM Posts
+        WHERE Posts.account != $1 AND (
+            Posts.posted > now() - make_interval(days => $6::int4)
+            OR Posts.account IN (SELECT account FROM followed)
+            OR Posts.project IN (SELECT project FROM member_of)
+        )
+        ORDER BY Posts.posted DESC NULLS LAST, Posts.id DESC
+        LIMIT $7::int8
+    )
+    SELECT candidates.id as "id!", candidates.account as "account!", candidates.image as "image!", candidates.title as "title!", candidates.description as "description!"
+    FROM candidates
+    LEFT JOIN Projects ON Projects.id = candidates.project
+    ORDER BY (
+        1.0
+        + CASE WHEN candidates.account IN (SELECT account FROM followed) THEN $2::float8 ELSE 0.0 END
+        + CASE WHEN candidates.project IN (SELECT project FROM member_of) THEN $3::float8 ELSE 0.0 END
+        + $4::float8 * (SELECT COUNT(*) FROM interests WHERE sdg = ANY(COALESCE(Projects.sdgs, '{}')))
+    ) / power(EXTRACT(EPOCH FROM (now() - candidates.posted)) / 3600 + 2, $5::float8) DESC, candidates.id DESC
+    OFFSET $8 LIMIT $9"#, user, FOLLOWED_WEIGHT, MEMBER_WEIGHT, SDG_WEIGHT, GRAVITY, CANDIDATE_WINDOW_DAYS, MAX_CANDIDATES, offset, limit)
+        .fetch_all(db)
+        .instrument(sql_span("feed.ranked_feed"))
+        .await?;
+
+    Ok(posts)
+}
+
+//used for anonymous viewers and accounts that opted out of personalization
+pub async fn chronological_feed(db: &DBClient, offset: i64, limit: i64) -> FieldResult<Vec<Post>> {
+    let posts = query_as!(Post, "SELECT id, account, image, title, description FROM Posts
+    ORDER BY posted DESC NULLS LAST, id DESC
+    OFFSET $1 LIMIT $2", offset, limit)
+        .fetch_all(db)
+        .instrument(sql_span("feed.chronological_feed"))
+        .await?;
+
+    Ok(posts)
+}
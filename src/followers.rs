@@ -1,4 +1,7 @@
-use crate::context::{get_db, get_redis_conn};
+use tracing::Instrument;
+use crate::telemetry::sql_span;
+use crate::context::{get_db, get_redis};
+use crate::cache::{cached, invalidate};
 use crate::auth::{get_auth};
 use sqlx::{query_as, query};
 use async_graphql::{FieldResult, Context};
@@ -6,6 +9,8 @@ use async_graphql_derive::*;
 use crate::schema::Account;
 use data::dataloader::ID;
 use data::data_macros::*;
+use data::sql_resolve::{SQLResolve, SQLTable, Page};
+use data::query::{Select, Order};
 use redis::AsyncCommands;
 use std::future::Future;
 
@@ -16,29 +21,33 @@ pub struct QueryFollowers {
 fn follower_count_key(id: ID) -> String { format!("{}:followers:count", id) }
 fn following_count_key(id: ID) -> String { format!("{}:following:count", id) }
 
+async fn invalidate_counts(ctx: &Context<'_>, follower: ID, following: ID) {
+    invalidate(get_redis(ctx), &[following_count_key(follower), follower_count_key(following)]).await;
+}
+
 const FOLLOWER_COUNT_EXPIRY : usize = 60;
+const FOLLOWERS_PAGE_LIMIT : i64 = 50;
 
 #[Object]
 impl QueryFollowers {
     //todo move out
     async fn find_accounts(&self, ctx: &Context<'_>, filter: String) -> FieldResult<Vec<Account>> {
-        let results = query_all_as!(ctx, Account, "SELECT id, username, profile FROM USERS
-	    WHERE
-            username LIKE $2 AND
-            id != $1
-            AND NOT EXISTS (SELECT * FROM Relationships WHERE following = id AND follower = $1)
-	    ", self.user, &filter);
+        let results = Select::<Account>::new()
+            .like("username", Some(filter))
+            .where_sql("Users.id != {}", Some(self.user))
+            .where_sql("NOT EXISTS (SELECT * FROM Relationships WHERE following = Users.id AND follower = {})", Some(self.user))
+            .fetch_all(ctx, get_db(ctx))
+            .instrument(sql_span("followers.find_accounts"))
+            .await?;
 
         Ok(results)
     }
 
-    async fn followers(&self, ctx: &Context<'_>) -> FieldResult<Vec<Account>> {
-        let results = query_all_as!(ctx, Account, "
-        SELECT Relationships.follower as id, Users.username, Users.profile
-        FROM Relationships
-        INNER join Users ON RELATIONSHIPS.follower = Users.id
-        WHERE following = $1;
-        ", self.user);
+    async fn followers(&self, ctx: &Context<'_>, cursor: Option<i64>, limit: Option<i64>) -> FieldResult<Vec<Account>> {
+        let page = Page::new("id", cursor, limit.unwrap_or(FOLLOWERS_PAGE_LIMIT));
+        let results = select_all_from!(ctx, Account, "
+        WHERE Users.id IN (SELECT follower FROM Relationships WHERE following = $1)
+        ", page = page, self.user);
 
         Ok(results)
     }
@@ -48,13 +57,11 @@ impl QueryFollowers {
     }
 
     async fn following(&self, ctx: &Context<'_>) -> FieldResult<Vec<Account>> {
-        let results = query_as!(Account, "
-        SELECT Relationships.follower as id, Users.username, Users.profile
-        FROM Relationships
-        INNER join Users ON RELATIONSHIPS.following = Users.id
-        WHERE follower = $1;
-        ", self.user)
-            .fetch_all(get_db(ctx))
+        let results = Select::<Account>::new()
+            .where_sql("Users.id IN (SELECT following FROM Relationships WHERE follower = {})", Some(self.user))
+            .order_by("id", Order::Asc)
+            .fetch_all(ctx, get_db(ctx))
+            .instrument(sql_span("followers.following"))
             .await?;
 
         Ok(results)
@@ -72,30 +79,39 @@ pub struct MutationFollowers;
 #[Object]
 impl MutationFollowers {
     async fn follow(&self, ctx: &Context<'_>, account: ID) -> FieldResult<bool> {
+        let user = get_auth(ctx)?.user;
         query!("
         INSERT INTO RELATIONSHIPS (follower, following)
         VALUES ($1, $2)
         ON CONFLICT DO NOTHING
-        ", get_auth(ctx)?.user, account)
-            .fetch_one(get_db(ctx))
+        ", user, account)
+            .execute(get_db(ctx))
+            .instrument(sql_span("followers.follow"))
             .await?;
 
+        invalidate_counts(ctx, user, account).await;
         Ok(true)
     }
 
     async fn unfollow(&self, ctx: &Context<'_>, account: ID) -> FieldResult<bool> {
-        query!("DELETE FROM RELATIONSHIPS WHERE follower = $1 and following = $2", get_auth(ctx)?.user, account)
-            .fetch_one(get_db(ctx))
+        let user = get_auth(ctx)?.user;
+        query!("DELETE FROM RELATIONSHIPS WHERE follower = $1 and following = $2", user, account)
+            .execute(get_db(ctx))
+            .instrument(sql_span("followers.unfollow"))
             .await?;
 
+        invalidate_counts(ctx, user, account).await;
         Ok(true)
     }
 
     async fn remove_follower(&self, ctx: &Context<'_>, account: ID) -> FieldResult<bool> {
-        query!("DELETE FROM RELATIONSHIPS WHERE follower = $1 and following = $2", account, get_auth(ctx)?.user)
-            .fetch_one(get_db(ctx))
+        let user = get_auth(ctx)?.user;
+        query!("DELETE FROM RELATIONSHIPS WHERE follower = $1 and following = $2", account, user)
+            .execute(get_db(ctx))
+            .instrument(sql_span("followers.remove_follower"))
             .await?;
 
+        invalidate_counts(ctx, account, user).await;
         Ok(true)
     }
 }
\ No newline at end of file
@@ -0,0 +1,362 @@
+use crate::auth::Auth;
+use crate::context::SharedContext;
+use crate::dataloaders::{make_viewer_loaders, Loaders};
+use crate::persisted_queries::{self, RequestExtensions};
+use crate::{errors, middleware, query_limits, telemetry, HTTPResponse};
+use async_graphql::http::GQLRequest;
+use async_graphql::{IntoQueryBuilder, QueryBuilder};
+use async_graphql_parser::query::{Definition, OperationType};
+use hyper::http::status::StatusCode;
+use hyper::header;
+use hyper::http::HeaderValue;
+use hyper::{Body, Method, Request, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::str;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{debug, info_span, Instrument};
+
+const MAX_BATCH_SIZE: usize = 16;
+
+#[derive(Serialize)]
+pub struct QueryResponseJSON {
+    data: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<Vec<errors::GraphQLError>>,
+}
+
+//the query can be left out when the client sends a persisted query hash instead
+#[derive(Deserialize)]
+struct GraphQLRequest {
+    query: Option<String>,
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+    variables: Option<serde_json::Value>,
+    #[serde(default)]
+    extensions: RequestExtensions,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BatchRequest {
+    Single(GraphQLRequest),
+    Batch(Vec<GraphQLRequest>),
+}
+
+#[derive(Deserialize)]
+struct GetParams {
+    query: Option<String>,
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+    variables: Option<String>,
+    extensions: Option<String>,
+}
+
+//a file from a multipart request, var_paths are the variables it is bound to, e.g. variables.file.
+//The file is streamed to disk and has to outlive the execution, since each variable reads it through its own handle
+struct UploadedFile {
+    var_paths: Vec<String>,
+    filename: String,
+    content_type: Option<String>,
+    file: tempfile::NamedTempFile,
+}
+
+//limits of a multipart request, enforced while the parts are streamed
+struct MultipartLimits {
+    max_files: usize,
+    max_file_bytes: usize,
+    max_body_bytes: usize,
+}
+
+pub fn json_response<T: Serialize>(status: StatusCode, body: &T) -> HTTPResponse {
+    let json = match serde_json::to_string(body) {
+        Ok(r) => r,
+        Err(e) => return HTTPResponse::Internal(format!("Could not serialize query {}", e)),
+    };
+
+    let mut resp = Response::new(Body::from(json));
+    *resp.status_mut() = status;
+    resp.headers_mut().insert("Content-Type", HeaderValue::from_static("application/json"));
+
+    HTTPResponse::Ok(resp)
+}
+
+fn request_error(message: String) -> (StatusCode, QueryResponseJSON) {
+    (StatusCode::BAD_REQUEST, QueryResponseJSON {
+        data: None,
+        errors: Some(vec![errors::GraphQLError::request(message)]),
+    })
+}
+
+//the executed operation is the named one, or the only one in the document
+fn is_mutation(request: &GQLRequest) -> Result<bool, String> {
+    let document = async_graphql_parser::parse_query(&request.query)
+        .map_err(|e| format!("Could not parse query: {}", e))?;
+
+    let mut operations = document.definitions.iter().filter_map(|definition| match &definition.node {
+        Definition::Operation(operation) => Some(&operation.node),
+        _ => None,
+    });
+
+    let operation = match &request.operation_name {
+        Some(name) => operations.find(|operation| operation.name.as_ref().map_or(false, |n| n.node.as_str() == name)),
+        None => operations.next(),
+    };
+
+    Ok(operation.map_or(false, |operation| operation.ty == OperationType::Mutation))
+}
+
+fn parse_get(req: &Request<Body>) -> Result<GraphQLRequest, String> {
+    let params: GetParams = serde_urlencoded::from_str(req.uri().query().unwrap_or(""))
+        .map_err(|e| format!("Could not parse query string: {}", e))?;
+
+    let variables = match params.variables {
+        Some(variables) => Some(serde_json::from_str(&variables).map_err(|e| format!("Could not parse variables: {}", e))?),
+        None => None,
+    };
+
+    let extensions = match params.extensions {
+        Some(extensions) => serde_json::from_str(&extensions).map_err(|e| format!("Could not parse extensions: {}", e))?,
+        None => RequestExtensions::default(),
+    };
+
+    Ok(GraphQLRequest {
+        query: params.query,
+        operation_name: params.operation_name,
+        variables,
+        extensions,
+    })
+}
+
+async fn parse_post(req: Request<Body>) -> Result<BatchRequest, String> {
+    let full_body_bytes = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|e| format!("Could not merge body {}", e))?;
+
+    let gql_query = str::from_utf8(&full_body_bytes)
+        .map_err(|_| "Body is not utf8 compliant".to_string())?;
+
+    serde_json::from_str(gql_query).map_err(|e| format!("Could not parse json: {}", e))
+}
+
+//writes the file part to a temporary file chunk by chunk, a file over the limit is rejected
+//as soon as the limit is crossed instead of after it has been received in full
+async fn stream_file(field: &mut multer::Field, name: &str, max_file_bytes: usize) -> Result<tempfile::NamedTempFile, String> {
+    let mut file = tempfile::NamedTempFile::new().map_err(|e| format!("Could not store upload: {}", e))?;
+    let mut written = 0;
+
+    while let Some(chunk) = field.chunk().await.map_err(|e| format!("Could not read file {}: {}", name, e))? {
+        written += chunk.len();
+        if written > max_file_bytes {
+            return Err(format!("Files are limited to {} bytes", max_file_bytes));
+        }
+        file.write_all(&chunk).map_err(|e| format!("Could not store upload: {}", e))?;
+    }
+
+    Ok(file)
+}
+
+//https://github.com/jaydenseric/graphql-multipart-request-spec, the operations and map parts come before the files
+async fn parse_multipart(req: Request<Body>, limits: MultipartLimits) -> Result<(GraphQLRequest, Vec<UploadedFile>), String> {
+    let boundary = req.headers().get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| multer::parse_boundary(content_type).ok())
+        .ok_or_else(|| "Missing multipart boundary".to_string())?;
+
+    let constraints = multer::Constraints::new()
+        .size_limit(multer::SizeLimit::new().whole_stream(limits.max_body_bytes as u64));
+    let mut multipart = multer::Multipart::new_with_constraints(req.into_body(), boundary, constraints);
+
+    let mut operations: Option<GraphQLRequest> = None;
+    let mut map: HashMap<String, Vec<String>> = HashMap::new();
+    let mut uploads = Vec::new();
+
+    while let Some(mut field) = multipart.next_field().await.map_err(|e| format!("Could not parse multipart body: {}", e))? {
+        let name = field.name().unwrap_or("").to_string();
+
+        match name.as_str() {
+            "operations" => {
+                let text = field.text().await.map_err(|e| format!("Could not read operations: {}", e))?;
+                operations = Some(serde_json::from_str(&text).map_err(|e| format!("Could not parse operations: {}", e))?);
+            }
+            "map" => {
+                let text = field.text().await.map_err(|e| format!("Could not read map: {}", e))?;
+                map = serde_json::from_str(&text).map_err(|e| format!("Could not parse map: {}", e))?;
+            }
+            _ => {
+                let var_paths = map.remove(&name).ok_or_else(|| format!("File {} is not in the map", name))?;
+                if uploads.len() == limits.max_files {
+                    return Err(format!("Requests are limited to {} files", limits.max_files));
+                }
+
+                let filename = field.file_name().unwrap_or("").to_string();
+                let content_type = field.content_type().map(|mime| mime.to_string());
+                let file = stream_file(&mut field, &name, limits.max_file_bytes).await?;
+
+                uploads.push(UploadedFile { var_paths, filename, content_type, file });
+            }
+        }
+    }
+
+    let operations = operations.ok_or_else(|| "Multipart request is missing operations".to_string())?;
+    Ok((operations, uploads))
+}
+
+//the second value is the hash to register the query under once it has been validated
+async fn resolve_query(shared: &SharedContext, request: GraphQLRequest) -> Result<(GQLRequest, Option<String>), (StatusCode, QueryResponseJSON)> {
+    let allowlist = shared.config.persisted_queries.allowlist;
+    match persisted_queries::resolve(&shared.redis, allowlist, request.query, &request.extensions).await {
+        Ok(resolved) => Ok((GQLRequest {
+            query: resolved.query,
+            operation_name: request.operation_name,
+            variables: request.variables,
+        }, resolved.register)),
+        Err(e) => {
+            let status = if e.code() == errors::ErrorCode::Validation { StatusCode::BAD_REQUEST } else { StatusCode::OK };
+            Err((status, QueryResponseJSON {
+                data: None,
+                errors: Some(vec![errors::GraphQLError::with_code(e.message(), e.code())]),
+            }))
+        }
+    }
+}
+
+async fn execute(shared: &Arc<SharedContext>, loaders: &Arc<Loaders>, auth: Option<Auth>, request: GraphQLRequest, uploads: Vec<UploadedFile>) -> (StatusCode, QueryResponseJSON) {
+    match resolve_query(shared, request).await {
+        Ok((gql_request, register)) => execute_resolved(shared, loaders, auth, gql_request, register, uploads).await,
+        Err(e) => e,
+    }
+}
+
+async fn execute_resolved(shared: &Arc<SharedContext>, loaders: &Arc<Loaders>, auth: Option<Auth>, gql_request: GQLRequest, register: Option<String>, uploads: Vec<UploadedFile>) -> (StatusCode, QueryResponseJSON) {
+    let operation = gql_request.operation_name.clone();
+    debug!(query = %gql_request.query, variables = %telemetry::redact_variables(&gql_request.variables), "GraphQL request");
+
+    //checked before execution so an expensive query never reaches the database
+    if let Err(e) = query_limits::check_limits(&shared.config.limits, &gql_request.query, &gql_request.operation_name, &gql_request.variables) {
+        return request_error(e);
+    }
+
+    let query_text = register.as_ref().map(|_| gql_request.query.clone());
+    let mut query: QueryBuilder = match gql_request.into_query_builder().await {
+        Ok(q) => q,
+        Err(e) => return request_error(format!("Could not parse query: {}", e)),
+    };
+
+    //each variable gets its own handle, so reading one does not move the position of the others
+    for upload in &uploads {
+        for var_path in &upload.var_paths {
+            let file = match upload.file.reopen() {
+                Ok(file) => file,
+                Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, QueryResponseJSON {
+                    data: None,
+                    errors: Some(vec![errors::GraphQLError::with_code(&format!("Could not store upload: {}", e), errors::ErrorCode::Internal)]),
+                }),
+            };
+            query.set_upload(var_path, upload.filename.clone(), upload.content_type.clone(), file);
+        }
+    }
+
+    let field_errors = Arc::new(errors::FieldErrors::default());
+    query = query.data(shared.clone()).data(loaders.clone()).data(field_errors.clone());
+    if let Some(auth) = auth {
+        query = query.data(make_viewer_loaders(shared.clone(), auth.user)).data(auth);
+    }
+
+    let started = Instant::now();
+    let span = info_span!("graphql", operation = operation.as_deref().unwrap_or("anonymous"));
+    let result = query.execute(&shared.schema).instrument(span).await;
+    shared.metrics.observe_graphql(operation.as_deref(), started.elapsed());
+
+    //parse and validation errors are only reported by execute, resolver errors mean the query itself was valid
+    let valid = !matches!(result, Err(async_graphql::Error::Parse(_)) | Err(async_graphql::Error::Rule { .. }));
+    if let (true, Some(hash), Some(query_text)) = (valid, register, query_text) {
+        persisted_queries::register(&shared.redis, &shared.config.persisted_queries, &hash, &query_text).await;
+    }
+
+    match result {
+        Ok(resp) => {
+            let errors = field_errors.take();
+            (StatusCode::OK, QueryResponseJSON {
+                data: Some(resp.data),
+                errors: if errors.is_empty() { None } else { Some(errors) },
+            })
+        }
+        Err(e) => {
+            //an error in a non null field stops execution, so there is no partial data to return
+            let (status, errors) = errors::to_graphql_errors(&e);
+            (status, QueryResponseJSON {
+                data: None,
+                errors: Some(errors),
+            })
+        }
+    }
+}
+
+pub async fn index_graphql(shared: Arc<SharedContext>, loaders: Arc<Loaders>, auth: Option<Auth>, req: Request<Body>) -> HTTPResponse {
+    //GET requests can be cached by a CDN, so they must never have side effects
+    if req.method() == Method::GET {
+        let gql_request = match parse_get(&req) {
+            Ok(r) => r,
+            Err(e) => return HTTPResponse::Error(StatusCode::BAD_REQUEST, e),
+        };
+
+        let (gql_request, register) = match resolve_query(&shared, gql_request).await {
+            Ok(r) => r,
+            Err((status, body)) => return json_response(status, &body),
+        };
+
+        match is_mutation(&gql_request) {
+            Ok(false) => {}
+            Ok(true) => return HTTPResponse::Error(StatusCode::METHOD_NOT_ALLOWED, "Mutations must be sent with POST".to_string()),
+            Err(e) => return HTTPResponse::Error(StatusCode::BAD_REQUEST, e),
+        }
+
+        let (status, body) = execute_resolved(&shared, &loaders, auth, gql_request, register, vec![]).await;
+        return json_response(status, &body);
+    }
+
+    if middleware::is_multipart(&req) {
+        let images = &shared.config.images;
+        let limits = MultipartLimits {
+            max_files: images.max_files_per_request,
+            max_file_bytes: images.max_upload_bytes,
+            max_body_bytes: shared.config.http.max_body_bytes + images.max_upload_bytes * images.max_files_per_request,
+        };
+
+        let (gql_request, uploads) = match parse_multipart(req, limits).await {
+            Ok(r) => r,
+            Err(e) => return HTTPResponse::Error(StatusCode::BAD_REQUEST, e),
+        };
+
+        let (status, body) = execute(&shared, &loaders, auth, gql_request, uploads).await;
+        return json_response(status, &body);
+    }
+
+    let batch = match parse_post(req).await {
+        Ok(r) => r,
+        Err(e) => return HTTPResponse::Error(StatusCode::BAD_REQUEST, e),
+    };
+
+    match batch {
+        BatchRequest::Single(gql_request) => {
+            let (status, body) = execute(&shared, &loaders, auth, gql_request, vec![]).await;
+            json_response(status, &body)
+        }
+        BatchRequest::Batch(gql_requests) => {
+            if gql_requests.len() > MAX_BATCH_SIZE {
+                return HTTPResponse::Error(StatusCode::BAD_REQUEST, format!("Batches are limited to {} operations", MAX_BATCH_SIZE));
+            }
+
+            //operations run concurrently, so their dataloader requests are batched together
+            let responses = futures::future::join_all(gql_requests.into_iter().map(|gql_request| {
+                execute(&shared, &loaders, auth.clone(), gql_request, vec![])
+            })).await;
+
+            let bodies: Vec<QueryResponseJSON> = responses.into_iter().map(|(_, body)| body).collect();
+            json_response(StatusCode::OK, &bodies)
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use tracing::Instrument;
+use crate::telemetry::sql_span;
+use crate::context::SharedContext;
+use crate::dataloaders::Loaders;
+use hyper::http::status::StatusCode;
+use hyper::http::HeaderValue;
+use hyper::{Body, Response};
+use serde::Serialize;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Serialize)]
+struct DependencyStatus {
+    name: &'static str,
+    ok: bool,
+    latency_ms: u128,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    dependencies: Vec<DependencyStatus>,
+}
+
+async fn check<F: Future<Output = Result<(), String>>>(name: &'static str, check: F) -> DependencyStatus {
+    let start = Instant::now();
+    let result = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("Timed out after {}ms", CHECK_TIMEOUT.as_millis())),
+    };
+
+    DependencyStatus {
+        name,
+        ok: result.is_ok(),
+        latency_ms: start.elapsed().as_millis(),
+        error: result.err(),
+    }
+}
+
+fn json_response(status: StatusCode, json: String) -> Response<Body> {
+    let mut resp = Response::new(Body::from(json));
+    *resp.status_mut() = status;
+    resp.headers_mut().insert("Content-Type", HeaderValue::from_static("application/json"));
+    resp
+}
+
+pub fn healthz() -> Response<Body> {
+    json_response(StatusCode::OK, r#"{"status":"ok"}"#.to_string())
+}
+
+pub async fn readyz(shared: &SharedContext, loaders: &Loaders) -> Result<Response<Body>, String> {
+    let postgres = check("postgres", async {
+        sqlx::query("SELECT 1")
+            .execute(&shared.db)
+            .instrument(sql_span("health.readyz"))
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    });
+
+    let redis = check("redis", async {
+        let mut conn = shared.redis.try_conn().await.map_err(|e| e.to_string())?;
+        redis::cmd("PING")
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .map_err(|e| e.to_string())
+    });
+
+    let analytics = check("analytics", async {
+        let alive = shared.analytics.workers_alive();
+        let expected = shared.analytics.num_workers();
+        if alive == expected { Ok(()) } else { Err(format!("{} of {} workers alive", alive, expected)) }
+    });
+
+    let dataloaders = check("dataloaders", async {
+        if loaders.running() { Ok(()) } else { Err("Dataloader task exited".to_string()) }
+    });
+
+    let (postgres, redis, analytics, dataloaders) = tokio::join!(postgres, redis, analytics, dataloaders);
+    let dependencies = vec![postgres, redis, analytics, dataloaders];
+    let ready = dependencies.iter().all(|dependency| dependency.ok);
+
+    let json = serde_json::to_string(&Readiness { ready, dependencies })
+        .map_err(|e| format!("Could not serialize readiness {}", e))?;
+
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    Ok(json_response(status, json))
+}
@@ -0,0 +1,690 @@
+use tracing::Instrument;
+use crate::telemetry::sql_span;
+use crate::context::{get_shared, SharedContext};
+use crate::moderation::{self, ModerationRequest, ModerationStatus};
+use crate::image_store::{BlobKey, ImageStore, StoreError};
+use crate::auth::get_auth;
+use crate::errors;
+use crate::schema::ImageID;
+use crate::dataloaders::get_loaders;
+use crate::HTTPResponse;
+use async_graphql_derive::*;
+use async_graphql::{Context, FieldResult, Upload};
+use chrono::Utc;
+use hyper::header;
+use hyper::http::status::StatusCode;
+use hyper::http::HeaderValue;
+use hyper::{Body, Request, Response};
+use image::imageops::FilterType;
+use image::{ImageEncoder, ImageOutputFormat};
+use serde::Deserialize;
+use sqlx::query;
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+//requested widths are rounded up to one of these, so every image has a bounded number of variants
+const VARIANT_WIDTHS: [u32; 4] = [160, 320, 640, 1080];
+const THUMB_WIDTH: u32 = 160;
+const MEDIUM_WIDTH: u32 = 640;
+
+//content types are detected from the first bytes of the file, the type sent by the client is ignored
+pub fn sniff_content_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+#[Enum]
+pub enum ImageSize {
+    Thumb,
+    Medium,
+    Full,
+}
+
+#[derive(Clone)]
+pub struct ImageInfo {
+    pub id: ImageID,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub contenttype: Option<String>,
+    pub blurhash: Option<String>,
+    pub status: String,
+}
+
+//only the id is known up front, the other fields are loaded in batches when they are selected
+pub struct Image {
+    pub id: ImageID,
+}
+
+impl Image {
+    pub fn new(id: ImageID) -> Image { Image { id } }
+
+    async fn info(&self, ctx: &Context<'_>) -> FieldResult<ImageInfo> {
+        let mut loader = get_loaders(ctx).image.clone();
+        Ok(loader.load(self.id).await?)
+    }
+}
+
+//width, height, mime type and blurhash are null for images uploaded before they were recorded,
+//or when loading them failed, in which case the error is returned next to the data
+#[Object]
+impl Image {
+    async fn id(&self) -> ImageID { self.id }
+
+    async fn url(&self, ctx: &Context<'_>, size: Option<ImageSize>) -> String {
+        let base = &get_shared(ctx).config.images.public_url;
+        match size {
+            Some(ImageSize::Thumb) => format!("{}/images/{}?size=thumb", base, self.id),
+            Some(ImageSize::Medium) => format!("{}/images/{}?size=medium", base, self.id),
+            Some(ImageSize::Full) | None => format!("{}/images/{}", base, self.id),
+        }
+    }
+
+    async fn width(&self, ctx: &Context<'_>) -> Option<i32> { errors::nullable(ctx, self.info(ctx).await.map(|info| info.width)) }
+    async fn height(&self, ctx: &Context<'_>) -> Option<i32> { errors::nullable(ctx, self.info(ctx).await.map(|info| info.height)) }
+    async fn mime_type(&self, ctx: &Context<'_>) -> Option<String> { errors::nullable(ctx, self.info(ctx).await.map(|info| info.contenttype)) }
+    async fn blurhash(&self, ctx: &Context<'_>) -> Option<String> { errors::nullable(ctx, self.info(ctx).await.map(|info| info.blurhash)) }
+    async fn status(&self, ctx: &Context<'_>) -> FieldResult<ModerationStatus> { Ok(ModerationStatus::from_db(&self.info(ctx).await?.status)) }
+}
+
+//reads at most one byte past the limit, so an oversized file is never fully loaded into memory
+fn read_upload(file: Upload, max_bytes: usize) -> FieldResult<Vec<u8>> {
+    let mut bytes = Vec::new();
+    file.into_read().take(max_bytes as u64 + 1).read_to_end(&mut bytes)?;
+
+    if bytes.len() > max_bytes {
+        return Err(errors::validation(&format!("Images are limited to {} bytes", max_bytes)));
+    }
+
+    Ok(bytes)
+}
+
+//reads only the header, so a small file claiming huge dimensions is never decoded
+pub fn image_dimensions(bytes: &[u8]) -> Result<(u32, u32), String> {
+    image::io::Reader::new(std::io::Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| format!("Could not read image {}", e))?
+        .into_dimensions()
+        .map_err(|e| format!("Could not read image dimensions {}", e))
+}
+
+//decoding also rejects files that only start like an image. Decoder errors are only logged,
+//their messages describe the internals of the decoder rather than what is wrong with the upload
+fn describe_image(bytes: &[u8], max_dimension: u32) -> FieldResult<(u32, u32, String)> {
+    let undecodable = |e: String| {
+        warn!(error = %e, "Could not decode uploaded image");
+        errors::validation("The image could not be decoded")
+    };
+
+    let (width, height) = image_dimensions(bytes).map_err(undecodable)?;
+    if width > max_dimension || height > max_dimension {
+        return Err(errors::validation(&format!("Image is larger than {}px", max_dimension)));
+    }
+
+    let img = image::load_from_memory(bytes).map_err(|e| undecodable(e.to_string()))?;
+
+    //the placeholder is blurred anyway, so a tiny copy is enough and much faster to encode
+    let small = img.thumbnail(32, 32).to_rgba8();
+    let blurhash = blurhash::encode(4, 3, small.width(), small.height(), small.as_raw());
+
+    Ok((img.width(), img.height(), blurhash))
+}
+
+#[derive(Default)]
+pub struct MutationImage;
+
+#[Object]
+impl MutationImage {
+    //returns the id to set as a post image, project image or group profile
+    async fn upload_image(&self, ctx: &Context<'_>, file: Upload) -> FieldResult<ImageID> {
+        let auth = get_auth(ctx)?;
+        let shared = get_shared(ctx);
+        let config = &shared.config.images;
+
+        //the upload is a temporary file, reading it would otherwise block the runtime
+        let max_upload_bytes = config.max_upload_bytes;
+        let bytes = tokio::task::spawn_blocking(move || read_upload(file, max_upload_bytes)).await??;
+        let content_type = match sniff_content_type(&bytes) {
+            Some(content_type) => content_type,
+            None => return Err(errors::validation("Only PNG, JPEG, GIF and WebP images can be uploaded")),
+        };
+
+        //uploads are public, so location data from the camera must never be stored
+        let bytes = match moderation::strip_metadata(&bytes, content_type) {
+            Some(bytes) => bytes,
+            None => return Err(errors::validation("The image could not be decoded")),
+        };
+
+        let described = bytes.clone();
+        let max_dimension = shared.config.moderation.max_dimension;
+        let (width, height, blurhash) = tokio::task::spawn_blocking(move || describe_image(&described, max_dimension)).await??;
+
+        let uploaded = Utc::now();
+        let image = query!("INSERT INTO Images (contenttype, width, height, blurhash, status, account, uploaded)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING id", content_type, width as i32, height as i32, blurhash, ModerationStatus::Pending.as_str(), auth.user, uploaded)
+            .fetch_one(&shared.db)
+            .instrument(sql_span("image.upload_image"))
+            .await?;
+
+        //an image without bytes would be served as a 404, so the row is removed again
+        if let Err(e) = shared.images.put(BlobKey::Original(image.id), &bytes, content_type).await {
+            query!("DELETE FROM Images WHERE id = $1", image.id)
+                .execute(&shared.db)
+                .instrument(sql_span("image.upload_image"))
+                .await?;
+            return Err(e.into());
+        }
+
+        //the image can only be published once it has been approved
+        let shared = ctx.data::<Arc<SharedContext>>()?.clone();
+        let request = ModerationRequest { id: image.id, bytes, content_type: content_type.to_string(), width, height };
+        tokio::spawn(async move { moderation::moderate_image(&shared, request).await });
+
+        Ok(image.id)
+    }
+}
+
+#[derive(Deserialize)]
+struct ImageParams {
+    size: Option<String>,
+    #[serde(alias = "width")]
+    w: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum VariantFormat {
+    Avif,
+    WebP,
+    Jpeg,
+    Png,
+}
+
+impl VariantFormat {
+    fn as_str(&self) -> &'static str {
+        match self {
+            VariantFormat::Avif => "avif",
+            VariantFormat::WebP => "webp",
+            VariantFormat::Jpeg => "jpeg",
+            VariantFormat::Png => "png",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            VariantFormat::Avif => "image/avif",
+            VariantFormat::WebP => "image/webp",
+            VariantFormat::Jpeg => "image/jpeg",
+            VariantFormat::Png => "image/png",
+        }
+    }
+}
+
+//None is the original width
+fn variant_width(params: &ImageParams) -> Result<Option<u32>, String> {
+    if let Some(w) = params.w {
+        return Ok(VARIANT_WIDTHS.iter().copied().find(|width| *width >= w));
+    }
+
+    match params.size.as_deref() {
+        None | Some("full") => Ok(None),
+        Some("thumb") => Ok(Some(THUMB_WIDTH)),
+        Some("medium") => Ok(Some(MEDIUM_WIDTH)),
+        Some(size) => Err(format!("Unknown image size {}, expected thumb, medium or full", size)),
+    }
+}
+
+//the q value of a media type in Accept, None when it is not listed
+fn accept_quality(accept: &str, media_type: &str) -> Option<f32> {
+    accept.split(',').find_map(|item| {
+        let mut parts = item.split(';');
+        if !parts.next().unwrap_or("").trim().eq_ignore_ascii_case(media_type) {
+            return None;
+        }
+
+        Some(parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .filter_map(|q| q.trim().parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0))
+    })
+}
+
+//browsers list the formats they can decode in Accept, q=0 explicitly refuses a format.
+//The original format is the fallback, since wildcards do not say whether avif or webp can be decoded
+fn negotiate_format(accept: Option<&str>, original: &str) -> VariantFormat {
+    let accept = accept.unwrap_or("");
+    let accepts = |media_type| accept_quality(accept, media_type).map_or(false, |q| q > 0.0);
+
+    if accepts("image/avif") {
+        VariantFormat::Avif
+    } else if accepts("image/webp") {
+        VariantFormat::WebP
+    } else if original == "image/png" {
+        VariantFormat::Png
+    } else {
+        VariantFormat::Jpeg
+    }
+}
+
+//decoding and encoding are cpu bound, so this runs on the blocking thread pool
+//images uploaded before dimensions were checked are checked here, before they are decoded
+fn encode_variant(original: &[u8], width: Option<u32>, format: VariantFormat, max_dimension: u32) -> Result<Vec<u8>, String> {
+    let (original_width, original_height) = image_dimensions(original)?;
+
+    if original_width > max_dimension || original_height > max_dimension {
+        return Err(format!("Image is larger than {}px", max_dimension));
+    }
+
+    let img = image::load_from_memory(original).map_err(|e| format!("Could not decode image {}", e))?;
+
+    //images are never upscaled
+    let img = match width {
+        Some(width) if width < img.width() => img.resize(width, u32::MAX, FilterType::Lanczos3),
+        _ => img,
+    };
+
+    let mut out = Vec::new();
+    match format {
+        VariantFormat::Jpeg => img.write_to(&mut out, ImageOutputFormat::Jpeg(82)).map_err(|e| format!("Could not encode jpeg {}", e))?,
+        VariantFormat::Png => img.write_to(&mut out, ImageOutputFormat::Png).map_err(|e| format!("Could not encode png {}", e))?,
+        VariantFormat::WebP => {
+            let rgba = img.to_rgba8();
+            out.extend_from_slice(&webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height()).encode(80.0));
+        }
+        VariantFormat::Avif => {
+            let rgba = img.to_rgba8();
+            image::codecs::avif::AvifEncoder::new_with_speed_quality(&mut out, 8, 70)
+                .write_image(&rgba, rgba.width(), rgba.height(), image::ColorType::Rgba8)
+                .map_err(|e| format!("Could not encode avif {}", e))?;
+        }
+    }
+
+    Ok(out)
+}
+
+fn variant_key(id: ImageID, width: Option<u32>, format: VariantFormat) -> BlobKey {
+    //width 0 is the original width
+    BlobKey::Variant { image: id, width: width.unwrap_or(0) as i32, format: format.as_str() }
+}
+
+//resizing would drop the animation of gifs
+fn needs_variant(content_type: &str, width: Option<u32>, format: VariantFormat) -> bool {
+    content_type != "image/gif" && (width.is_some() || format.content_type() != content_type)
+}
+
+//one generation per variant at a time, concurrent first requests for the same variant wait for it
+//instead of all decoding the original. Entries are removed once nobody is waiting for them
+#[derive(Default)]
+pub struct VariantLocks(std::sync::Mutex<HashMap<(ImageID, i32, &'static str), Arc<tokio::sync::Mutex<()>>>>);
+
+impl VariantLocks {
+    fn lock_for(&self, key: (ImageID, i32, &'static str)) -> Arc<tokio::sync::Mutex<()>> {
+        self.0.lock().unwrap().entry(key).or_default().clone()
+    }
+
+    fn release(&self, key: (ImageID, i32, &'static str), lock: Arc<tokio::sync::Mutex<()>>) {
+        let mut locks = self.0.lock().unwrap();
+        //the map and this request hold the only references
+        if Arc::strong_count(&lock) == 2 {
+            locks.remove(&key);
+        }
+    }
+}
+
+//variants are generated on first request and kept in the image store
+async fn generate_variant(shared: &SharedContext, id: ImageID, original: Vec<u8>, width: Option<u32>, format: VariantFormat) -> Result<Vec<u8>, String> {
+    let key = variant_key(id, width, format);
+    let lock_key = (id, width.unwrap_or(0) as i32, format.as_str());
+    let lock = shared.variant_locks.lock_for(lock_key);
+    let guard = lock.lock().await;
+
+    let result = match shared.images.get(key).await {
+        Ok(Some(bytes)) => Ok(bytes),
+        Ok(None) => encode_and_store(shared, id, original, width, format).await,
+        Err(e) => Err(format!("Could not load image variant {}", e)),
+    };
+
+    drop(guard);
+    shared.variant_locks.release(lock_key, lock);
+    result
+}
+
+async fn encode_and_store(shared: &SharedContext, id: ImageID, original: Vec<u8>, width: Option<u32>, format: VariantFormat) -> Result<Vec<u8>, String> {
+    let max_dimension = shared.config.images.max_variant_dimension;
+    let bytes = tokio::task::spawn_blocking(move || encode_variant(&original, width, format, max_dimension))
+        .await
+        .map_err(|e| format!("Image encoder panicked {}", e))??;
+
+    debug!(image = id, width = width.unwrap_or(0), format = format.as_str(), size = bytes.len(), "Generated image variant");
+
+    shared.images.put(variant_key(id, width, format), &bytes, format.content_type())
+        .await
+        .map_err(|e| format!("Could not store image variant {}", e))?;
+
+    Ok(bytes)
+}
+
+//image ids are never reused and stored bytes never change, so responses can be cached forever.
+//Pending images can still be rejected, so they must not be cached at all
+const CACHE_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+const CACHE_NONE: &str = "no-store";
+
+//the bytes of an image or variant never change, so the tag is derived from what is served instead of
+//hashing the bytes, which lets a revalidation be answered without loading the blob
+fn etag(id: ImageID, variant: Option<(Option<u32>, VariantFormat)>) -> String {
+    match variant {
+        Some((width, format)) => format!("\"{}-{}-{}\"", id, width.unwrap_or(0), format.as_str()),
+        None => format!("\"{}-original\"", id),
+    }
+}
+
+fn not_modified(req: &Request<Body>, etag: &str) -> bool {
+    req.headers().get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |if_none_match| etag_matches(if_none_match, etag))
+}
+
+//If-None-Match can list several tags, weak tags match since the bytes are never transformed
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').any(|tag| {
+        let tag = tag.trim();
+        tag == "*" || tag.trim_start_matches("W/") == etag
+    })
+}
+
+//only a single range is supported, anything else is answered with the full image
+fn parse_range(range: &str, len: usize) -> Option<Result<(usize, usize), ()>> {
+    let range = range.trim().strip_prefix("bytes=")?;
+    if range.contains(',') {
+        return None;
+    }
+
+    let (start, end) = range.split_at(range.find('-')?);
+    let end = &end[1..];
+
+    let bounds = match (start.trim(), end.trim()) {
+        ("", "") => return None,
+        ("", suffix) => {
+            let suffix: usize = suffix.parse().ok()?;
+            if suffix == 0 { return Some(Err(())) }
+            (len.saturating_sub(suffix), len.checked_sub(1))
+        }
+        (start, "") => (start.parse().ok()?, len.checked_sub(1)),
+        (start, end) => {
+            let (start, end): (usize, usize) = (start.parse().ok()?, end.parse().ok()?);
+            //a range that ends before it starts is invalid rather than unsatisfiable, so it is ignored
+            if end < start { return None }
+            (start, Some(end.min(len.saturating_sub(1))))
+        }
+    };
+
+    match bounds {
+        (start, Some(end)) if start <= end && start < len => Some(Ok((start, end))),
+        _ => Some(Err(())),
+    }
+}
+
+fn cached_response(etag: &str, cache_control: &'static str) -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+    let headers = resp.headers_mut();
+    headers.insert(header::VARY, HeaderValue::from_static("Accept"));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(cache_control));
+    if let Ok(value) = HeaderValue::from_str(etag) {
+        headers.insert(header::ETAG, value);
+    }
+    resp
+}
+
+fn not_modified_response(etag: &str, cache_control: &'static str) -> HTTPResponse {
+    let mut resp = cached_response(etag, cache_control);
+    *resp.status_mut() = StatusCode::NOT_MODIFIED;
+    HTTPResponse::Ok(resp)
+}
+
+enum ImageBody {
+    Full(Vec<u8>),
+    Partial { bytes: Vec<u8>, start: usize, end: usize, len: usize },
+    Unsatisfiable { len: usize },
+}
+
+//a stale If-Range means the client's partial copy is outdated, so it gets the whole image
+fn requested_range<'a>(req: &'a Request<Body>, etag: &str) -> Option<&'a str> {
+    let header_str = |name: header::HeaderName| req.headers().get(name).and_then(|value: &HeaderValue| value.to_str().ok());
+
+    let range_valid = header_str(header::IF_RANGE).map_or(true, |if_range| if_range == etag);
+    header_str(header::RANGE).filter(|_| range_valid)
+}
+
+//for images that are already in memory, e.g. a variant that was just generated
+fn body_from_bytes(req: &Request<Body>, bytes: Vec<u8>, etag: &str) -> ImageBody {
+    let len = bytes.len();
+    match requested_range(req, etag).and_then(|range| parse_range(range, len)) {
+        None => ImageBody::Full(bytes),
+        Some(Ok((start, end))) => ImageBody::Partial { bytes: bytes[start..=end].to_vec(), start, end, len },
+        Some(Err(())) => ImageBody::Unsatisfiable { len },
+    }
+}
+
+//range requests, e.g. resumed downloads of large originals, only load the requested bytes from the store
+async fn load_body(req: &Request<Body>, store: &dyn ImageStore, key: BlobKey, etag: &str) -> Result<Option<ImageBody>, StoreError> {
+    let range = match requested_range(req, etag) {
+        Some(range) => range,
+        None => return Ok(store.get(key).await?.map(ImageBody::Full)),
+    };
+
+    let len = match store.len(key).await? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+
+    match parse_range(range, len) {
+        None => Ok(store.get(key).await?.map(ImageBody::Full)),
+        Some(Ok((start, end))) => Ok(store.get_range(key, start, end).await?.map(|bytes| ImageBody::Partial { bytes, start, end, len })),
+        Some(Err(())) => Ok(Some(ImageBody::Unsatisfiable { len })),
+    }
+}
+
+fn image_response(req: &Request<Body>, body: ImageBody, content_type: &str, cache_control: &'static str, etag: &str) -> HTTPResponse {
+    if not_modified(req, etag) {
+        return not_modified_response(etag, cache_control);
+    }
+
+    let mut resp = cached_response(etag, cache_control);
+    let headers = resp.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap_or_else(|_| HeaderValue::from_static("application/octet-stream")));
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    match body {
+        ImageBody::Full(bytes) => *resp.body_mut() = Body::from(bytes),
+        ImageBody::Partial { bytes, start, end, len } => {
+            *resp.status_mut() = StatusCode::PARTIAL_CONTENT;
+            if let Ok(value) = HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, len)) {
+                resp.headers_mut().insert(header::CONTENT_RANGE, value);
+            }
+            *resp.body_mut() = Body::from(bytes);
+        }
+        ImageBody::Unsatisfiable { len } => {
+            *resp.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            if let Ok(value) = HeaderValue::from_str(&format!("bytes */{}", len)) {
+                resp.headers_mut().insert(header::CONTENT_RANGE, value);
+            }
+        }
+    }
+
+    HTTPResponse::Ok(resp)
+}
+
+//serves /images/{id}?size=thumb|medium|full or /images/{id}?w=320
+pub async fn index_image(shared: &SharedContext, req: Request<Body>) -> HTTPResponse {
+    let path = req.uri().path();
+    let id: ImageID = match path.strip_prefix("/images/").and_then(|id| id.parse().ok()) {
+        Some(id) => id,
+        None => return HTTPResponse::Error(StatusCode::NOT_FOUND, format!("Could not find {}", path)),
+    };
+
+    let params: ImageParams = match serde_urlencoded::from_str(req.uri().query().unwrap_or("")) {
+        Ok(params) => params,
+        Err(e) => return HTTPResponse::Error(StatusCode::BAD_REQUEST, format!("Could not parse query string: {}", e)),
+    };
+
+    let width = match variant_width(&params) {
+        Ok(width) => width,
+        Err(e) => return HTTPResponse::Error(StatusCode::BAD_REQUEST, e),
+    };
+
+    let (stored_type, status) = match query!("SELECT contenttype, status FROM Images WHERE id = $1", id)
+        .fetch_optional(&shared.db)
+        .instrument(sql_span("image.index_image"))
+        .await {
+        Ok(Some(result)) => (result.contenttype, ModerationStatus::from_db(&result.status)),
+        Ok(None) => return HTTPResponse::Error(StatusCode::NOT_FOUND, "".to_string()),
+        Err(e) => return HTTPResponse::Internal(format!("Could not load image {}", e)),
+    };
+
+    //rejected images are indistinguishable from missing ones
+    let cache_control = match status {
+        ModerationStatus::Approved => CACHE_IMMUTABLE,
+        ModerationStatus::Pending => CACHE_NONE,
+        ModerationStatus::Rejected => return HTTPResponse::Error(StatusCode::NOT_FOUND, "".to_string()),
+    };
+
+    let accept = req.headers().get(header::ACCEPT).and_then(|accept| accept.to_str().ok());
+
+    //revalidations, stored variants and originals served as they are never need the original in memory
+    if let Some(content_type) = &stored_type {
+        let format = negotiate_format(accept, content_type);
+        let variant = Some((width, format)).filter(|_| needs_variant(content_type, width, format));
+        let etag = etag(id, variant);
+        if not_modified(&req, &etag) {
+            return not_modified_response(&etag, cache_control);
+        }
+
+        let (key, served_type) = match variant {
+            Some(_) => (variant_key(id, width, format), format.content_type()),
+            None => (BlobKey::Original(id), content_type.as_str()),
+        };
+
+        //a missing variant is generated below, a missing original is a 404 either way
+        match load_body(&req, shared.images.as_ref(), key, &etag).await {
+            Ok(Some(body)) => return image_response(&req, body, served_type, cache_control, &etag),
+            Ok(None) => {}
+            Err(e) => return HTTPResponse::Internal(e.to_string()),
+        }
+    }
+
+    let original = match shared.images.get(BlobKey::Original(id)).await {
+        Ok(Some(original)) => original,
+        Ok(None) => return HTTPResponse::Error(StatusCode::NOT_FOUND, "".to_string()),
+        Err(e) => return HTTPResponse::Internal(e.to_string()),
+    };
+
+    //images uploaded before content types were stored are sniffed once and backfilled
+    let content_type = match stored_type {
+        Some(content_type) => content_type,
+        None => {
+            let content_type = sniff_content_type(&original).unwrap_or("application/octet-stream");
+            if let Err(e) = query!("UPDATE Images SET contenttype = $2 WHERE id = $1 AND contenttype IS NULL", id, content_type)
+                .execute(&shared.db)
+                .instrument(sql_span("image.index_image"))
+                .await {
+                warn!(image = id, error = %e, "Could not backfill image content type");
+            }
+            content_type.to_string()
+        }
+    };
+
+    let format = negotiate_format(accept, &content_type);
+    if !needs_variant(&content_type, width, format) {
+        let etag = etag(id, None);
+        return image_response(&req, body_from_bytes(&req, original, &etag), &content_type, cache_control, &etag);
+    }
+
+    match generate_variant(shared, id, original, width, format).await {
+        Ok(bytes) => {
+            let etag = etag(id, Some((width, format)));
+            image_response(&req, body_from_bytes(&req, bytes, &etag), format.content_type(), cache_control, &etag)
+        }
+        Err(e) => HTTPResponse::Internal(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variant_locks_are_shared_and_released() {
+        let locks = VariantLocks::default();
+        let key = (1, 320, "webp");
+
+        let first = locks.lock_for(key);
+        let second = locks.lock_for(key);
+        assert!(Arc::ptr_eq(&first, &second));
+
+        locks.release(key, first);
+        assert_eq!(locks.0.lock().unwrap().len(), 1);
+        locks.release(key, second);
+        assert!(locks.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn prefers_avif_then_webp() {
+        assert_eq!(negotiate_format(Some("image/avif,image/webp,image/apng,*/*;q=0.8"), "image/jpeg"), VariantFormat::Avif);
+        assert_eq!(negotiate_format(Some("image/webp,*/*"), "image/jpeg"), VariantFormat::WebP);
+    }
+
+    #[test]
+    fn falls_back_to_the_original_format() {
+        assert_eq!(negotiate_format(None, "image/png"), VariantFormat::Png);
+        assert_eq!(negotiate_format(Some("*/*"), "image/jpeg"), VariantFormat::Jpeg);
+    }
+
+    #[test]
+    fn refused_formats_are_skipped() {
+        assert_eq!(negotiate_format(Some("image/avif;q=0, image/webp"), "image/jpeg"), VariantFormat::WebP);
+        assert_eq!(negotiate_format(Some("image/avif; q=0.0, image/webp;q=0"), "image/png"), VariantFormat::Png);
+        assert_eq!(negotiate_format(Some("image/avif;q=0.5"), "image/png"), VariantFormat::Avif);
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100), Some(Ok((0, 9))));
+        assert_eq!(parse_range("bytes=90-", 100), Some(Ok((90, 99))));
+        assert_eq!(parse_range("bytes=-10", 100), Some(Ok((90, 99))));
+        assert_eq!(parse_range("bytes=-1000", 100), Some(Ok((0, 99))));
+        assert_eq!(parse_range("bytes=50-1000", 100), Some(Ok((50, 99))));
+    }
+
+    #[test]
+    fn ignores_invalid_ranges() {
+        assert_eq!(parse_range("bytes=5-3", 100), None);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range("items=0-1", 100), None);
+        assert_eq!(parse_range("bytes=-", 100), None);
+        assert_eq!(parse_range("bytes=a-b", 100), None);
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=100-", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=100-200", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-", 0), Some(Err(())));
+    }
+
+    #[test]
+    fn media_types_are_case_insensitive() {
+        assert_eq!(negotiate_format(Some("Image/WebP"), "image/jpeg"), VariantFormat::WebP);
+    }
+}
@@ -0,0 +1,453 @@
+use tracing::Instrument;
+use crate::telemetry::sql_span;
+use crate::config::{ImageConfig, StorageBackend};
+use crate::context::DBClient;
+use crate::schema::ImageID;
+use async_trait::async_trait;
+use sqlx::query;
+use std::fmt::{Display, Formatter};
+use std::io::{SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::info;
+
+#[derive(Clone, Copy, Debug)]
+pub enum BlobKey {
+    Original(ImageID),
+    Variant { image: ImageID, width: i32, format: &'static str },
+}
+
+impl BlobKey {
+    //used as the file path and object key, variants live next to their original so they are easy to clean up
+    pub fn path(&self) -> String {
+        match self {
+            BlobKey::Original(image) => format!("images/{}/original", image),
+            BlobKey::Variant { image, width, format } => format!("images/{}/{}.{}", image, width, format),
+        }
+    }
+}
+
+//variant formats are stored as file extensions, keys need them as static strings
+fn variant_format(format: &str) -> Option<&'static str> {
+    ["avif", "webp", "jpeg", "png"].iter().copied().find(|known| *known == format)
+}
+
+//the inverse of BlobKey::path for variants, other files such as the original are skipped
+fn parse_variant(image: ImageID, file_name: &str) -> Option<BlobKey> {
+    let (width, format) = file_name.split_at(file_name.find('.')?);
+    Some(BlobKey::Variant { image, width: width.parse().ok()?, format: variant_format(&format[1..])? })
+}
+
+fn variant_content_type(format: &str) -> String {
+    format!("image/{}", format)
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Database(sqlx::Error),
+    Io(std::io::Error),
+    S3(String),
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Database(e) => write!(f, "Database error in image store: {}", e),
+            StoreError::Io(e) => write!(f, "IO error in image store: {}", e),
+            StoreError::S3(e) => write!(f, "S3 error in image store: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<sqlx::Error> for StoreError {
+    fn from(e: sqlx::Error) -> Self { StoreError::Database(e) }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self { StoreError::Io(e) }
+}
+
+//image metadata always stays in the Images table, only the bytes go through the store
+#[async_trait]
+pub trait ImageStore: Send + Sync {
+    fn name(&self) -> &'static str;
+    async fn get(&self, key: BlobKey) -> Result<Option<Vec<u8>>, StoreError>;
+    //range requests only load the requested bytes, end is inclusive like in a Range header
+    async fn len(&self, key: BlobKey) -> Result<Option<usize>, StoreError>;
+    async fn get_range(&self, key: BlobKey, start: usize, end: usize) -> Result<Option<Vec<u8>>, StoreError>;
+    async fn put(&self, key: BlobKey, bytes: &[u8], content_type: &str) -> Result<(), StoreError>;
+    async fn delete(&self, key: BlobKey) -> Result<(), StoreError>;
+    //the variants generated for an image so far
+    async fn variants(&self, image: ImageID) -> Result<Vec<BlobKey>, StoreError>;
+}
+
+pub struct PostgresStore {
+    db: DBClient,
+}
+
+#[async_trait]
+impl ImageStore for PostgresStore {
+    fn name(&self) -> &'static str { "postgres" }
+
+    async fn get(&self, key: BlobKey) -> Result<Option<Vec<u8>>, StoreError> {
+        match key {
+            BlobKey::Original(image) => {
+                let row = query!("SELECT highres FROM Images WHERE id = $1", image)
+                    .fetch_optional(&self.db)
+                    .instrument(sql_span("image_store.get"))
+                    .await?;
+                Ok(row.and_then(|row| row.highres))
+            }
+            BlobKey::Variant { image, width, format } => {
+                let row = query!("SELECT bytes FROM ImageVariants WHERE image = $1 AND width = $2 AND format = $3", image, width, format)
+                    .fetch_optional(&self.db)
+                    .instrument(sql_span("image_store.get"))
+                    .await?;
+                Ok(row.map(|row| row.bytes))
+            }
+        }
+    }
+
+    async fn len(&self, key: BlobKey) -> Result<Option<usize>, StoreError> {
+        let len = match key {
+            BlobKey::Original(image) => query!("SELECT octet_length(highres) AS len FROM Images WHERE id = $1", image)
+                .fetch_optional(&self.db)
+                .instrument(sql_span("image_store.len"))
+                .await?
+                .and_then(|row| row.len),
+            BlobKey::Variant { image, width, format } => query!("SELECT octet_length(bytes) AS len FROM ImageVariants WHERE image = $1 AND width = $2 AND format = $3", image, width, format)
+                .fetch_optional(&self.db)
+                .instrument(sql_span("image_store.len"))
+                .await?
+                .and_then(|row| row.len),
+        };
+        Ok(len.map(|len| len as usize))
+    }
+
+    //substring of bytea is 1 based
+    async fn get_range(&self, key: BlobKey, start: usize, end: usize) -> Result<Option<Vec<u8>>, StoreError> {
+        let (from, count) = (start as i32 + 1, (end - start + 1) as i32);
+        let bytes = match key {
+            BlobKey::Original(image) => query!("SELECT substring(highres FROM $2 FOR $3) AS bytes FROM Images WHERE id = $1", image, from, count)
+                .fetch_optional(&self.db)
+                .instrument(sql_span("image_store.get_range"))
+                .await?
+                .and_then(|row| row.bytes),
+            BlobKey::Variant { image, width, format } => query!("SELECT substring(bytes FROM $4 FOR $5) AS bytes FROM ImageVariants WHERE image = $1 AND width = $2 AND format = $3", image, width, format, from, count)
+                .fetch_optional(&self.db)
+                .instrument(sql_span("image_store.get_range"))
+                .await?
+                .and_then(|row| row.bytes),
+        };
+        Ok(bytes)
+    }
+
+    async fn put(&self, key: BlobKey, bytes: &[u8], _content_type: &str) -> Result<(), StoreError> {
+        match key {
+            BlobKey::Original(image) => {
+                query!("UPDATE Images SET highres = $2 WHERE id = $1", image, bytes)
+                    .execute(&self.db)
+                    .instrument(sql_span("image_store.put"))
+                    .await?;
+            }
+            //concurrent first requests can both generate a variant, the first one to finish is kept
+            BlobKey::Variant { image, width, format } => {
+                query!("INSERT INTO ImageVariants (image, width, format, bytes) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+                    image, width, format, bytes)
+                    .execute(&self.db)
+                    .instrument(sql_span("image_store.put"))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, key: BlobKey) -> Result<(), StoreError> {
+        match key {
+            BlobKey::Original(image) => {
+                query!("UPDATE Images SET highres = NULL WHERE id = $1", image)
+                    .execute(&self.db)
+                    .instrument(sql_span("image_store.delete"))
+                    .await?;
+            }
+            BlobKey::Variant { image, width, format } => {
+                query!("DELETE FROM ImageVariants WHERE image = $1 AND width = $2 AND format = $3", image, width, format)
+                    .execute(&self.db)
+                    .instrument(sql_span("image_store.delete"))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn variants(&self, image: ImageID) -> Result<Vec<BlobKey>, StoreError> {
+        let rows = query!("SELECT width, format FROM ImageVariants WHERE image = $1", image)
+            .fetch_all(&self.db)
+            .instrument(sql_span("image_store.variants"))
+            .await?;
+
+        Ok(rows.into_iter()
+            .filter_map(|row| Some(BlobKey::Variant { image, width: row.width, format: variant_format(&row.format)? }))
+            .collect())
+    }
+}
+
+pub struct FilesystemStore {
+    root: PathBuf,
+}
+
+#[async_trait]
+impl ImageStore for FilesystemStore {
+    fn name(&self) -> &'static str { "filesystem" }
+
+    async fn get(&self, key: BlobKey) -> Result<Option<Vec<u8>>, StoreError> {
+        match tokio::fs::read(self.root.join(key.path())).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn len(&self, key: BlobKey) -> Result<Option<usize>, StoreError> {
+        match tokio::fs::metadata(self.root.join(key.path())).await {
+            Ok(metadata) => Ok(Some(metadata.len() as usize)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_range(&self, key: BlobKey, start: usize, end: usize) -> Result<Option<Vec<u8>>, StoreError> {
+        let mut file = match tokio::fs::File::open(self.root.join(key.path())).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        file.seek(SeekFrom::Start(start as u64)).await?;
+        let mut bytes = vec![0; end - start + 1];
+        file.read_exact(&mut bytes).await?;
+        Ok(Some(bytes))
+    }
+
+    //written to a uniquely named temporary file in the same directory first, so readers never see a
+    //partially written image and concurrent writers of the same key never share a temporary file
+    async fn put(&self, key: BlobKey, bytes: &[u8], _content_type: &str) -> Result<(), StoreError> {
+        let path = self.root.join(key.path());
+        let parent = path.parent().map_or_else(|| self.root.clone(), |parent| parent.to_path_buf());
+        tokio::fs::create_dir_all(&parent).await?;
+
+        let bytes = bytes.to_vec();
+        tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+            let mut tmp = tempfile::NamedTempFile::new_in(&parent)?;
+            tmp.write_all(&bytes)?;
+            tmp.persist(&path).map_err(|e| e.error)?;
+            Ok(())
+        }).await.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))??;
+
+        Ok(())
+    }
+
+    async fn delete(&self, key: BlobKey) -> Result<(), StoreError> {
+        match tokio::fs::remove_file(self.root.join(key.path())).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn variants(&self, image: ImageID) -> Result<Vec<BlobKey>, StoreError> {
+        let mut entries = match tokio::fs::read_dir(self.root.join(format!("images/{}", image))).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut variants = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(variant) = entry.file_name().to_str().and_then(|name| parse_variant(image, name)) {
+                variants.push(variant);
+            }
+        }
+        Ok(variants)
+    }
+}
+
+//works against AWS as well as local stand-ins such as MinIO, which need path style urls
+pub struct S3Store {
+    bucket: s3::bucket::Bucket,
+}
+
+impl S3Store {
+    fn new(config: &ImageConfig) -> Result<S3Store, StoreError> {
+        let region = s3::region::Region::Custom {
+            region: config.s3_region.clone(),
+            endpoint: config.s3_endpoint.clone(),
+        };
+
+        let credentials = s3::creds::Credentials::new(Some(&config.s3_access_key), Some(&config.s3_secret_key), None, None, None)
+            .map_err(|e| StoreError::S3(e.to_string()))?;
+
+        let bucket = s3::bucket::Bucket::new_with_path_style(&config.s3_bucket, region, credentials)
+            .map_err(|e| StoreError::S3(e.to_string()))?;
+
+        Ok(S3Store { bucket })
+    }
+}
+
+fn check_status(status: u16, key: BlobKey) -> Result<(), StoreError> {
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        Err(StoreError::S3(format!("Unexpected status {} for {}", status, key.path())))
+    }
+}
+
+#[async_trait]
+impl ImageStore for S3Store {
+    fn name(&self) -> &'static str { "s3" }
+
+    async fn get(&self, key: BlobKey) -> Result<Option<Vec<u8>>, StoreError> {
+        let (bytes, status) = self.bucket.get_object(&key.path()).await
+            .map_err(|e| StoreError::S3(e.to_string()))?;
+
+        if status == 404 {
+            return Ok(None);
+        }
+        check_status(status, key)?;
+        Ok(Some(bytes))
+    }
+
+    //listing with the key as the prefix returns the size without downloading the object
+    async fn len(&self, key: BlobKey) -> Result<Option<usize>, StoreError> {
+        let path = key.path();
+        let pages = self.bucket.list(path.clone(), None).await
+            .map_err(|e| StoreError::S3(e.to_string()))?;
+
+        Ok(pages.iter()
+            .flat_map(|page| page.contents.iter())
+            .find(|object| object.key == path)
+            .map(|object| object.size as usize))
+    }
+
+    async fn get_range(&self, key: BlobKey, start: usize, end: usize) -> Result<Option<Vec<u8>>, StoreError> {
+        let (bytes, status) = self.bucket.get_object_range(&key.path(), start as u64, Some(end as u64)).await
+            .map_err(|e| StoreError::S3(e.to_string()))?;
+
+        if status == 404 {
+            return Ok(None);
+        }
+        check_status(status, key)?;
+        Ok(Some(bytes))
+    }
+
+    async fn put(&self, key: BlobKey, bytes: &[u8], content_type: &str) -> Result<(), StoreError> {
+        let (_, status) = self.bucket.put_object_with_content_type(&key.path(), bytes, content_type).await
+            .map_err(|e| StoreError::S3(e.to_string()))?;
+        check_status(status, key)
+    }
+
+    async fn delete(&self, key: BlobKey) -> Result<(), StoreError> {
+        let (_, status) = self.bucket.delete_object(&key.path()).await
+            .map_err(|e| StoreError::S3(e.to_string()))?;
+
+        if status == 404 {
+            return Ok(());
+        }
+        check_status(status, key)
+    }
+
+    async fn variants(&self, image: ImageID) -> Result<Vec<BlobKey>, StoreError> {
+        let prefix = format!("images/{}/", image);
+        let pages = self.bucket.list(prefix.clone(), None).await
+            .map_err(|e| StoreError::S3(e.to_string()))?;
+
+        Ok(pages.iter()
+            .flat_map(|page| page.contents.iter())
+            .filter_map(|object| parse_variant(image, object.key.strip_prefix(&prefix)?))
+            .collect())
+    }
+}
+
+pub fn make_image_store(backend: StorageBackend, config: &ImageConfig, db: &DBClient) -> Result<Arc<dyn ImageStore>, StoreError> {
+    Ok(match backend {
+        StorageBackend::Postgres => Arc::new(PostgresStore { db: db.clone() }),
+        StorageBackend::Filesystem => Arc::new(FilesystemStore { root: PathBuf::from(&config.storage_path) }),
+        StorageBackend::S3 => Arc::new(S3Store::new(config)?),
+    })
+}
+
+async fn copy(from: &dyn ImageStore, to: &dyn ImageStore, key: BlobKey, content_type: &str, delete: bool) -> Result<bool, StoreError> {
+    let bytes = match from.get(key).await? {
+        Some(bytes) => bytes,
+        None => return Ok(false),
+    };
+
+    to.put(key, &bytes, content_type).await?;
+
+    //the source copy is only removed once the destination has it
+    if delete {
+        from.delete(key).await?;
+    }
+    Ok(true)
+}
+
+//copies originals and their generated variants between stores, so nothing is left behind in the source store
+pub async fn migrate(db: &DBClient, from: &dyn ImageStore, to: &dyn ImageStore, delete: bool) -> Result<usize, StoreError> {
+    if from.name() == to.name() {
+        return Err(StoreError::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Can not migrate images to the store they are in")));
+    }
+
+    let images = query!("SELECT id, contenttype FROM Images ORDER BY id")
+        .fetch_all(db)
+        .instrument(sql_span("image_store.migrate"))
+        .await?;
+
+    let mut migrated = 0;
+    for image in images {
+        let content_type = image.contenttype.as_deref().unwrap_or("application/octet-stream");
+        if !copy(from, to, BlobKey::Original(image.id), content_type, delete).await? {
+            continue;
+        }
+
+        for variant in from.variants(image.id).await? {
+            if let BlobKey::Variant { format, .. } = variant {
+                copy(from, to, variant, &variant_content_type(format), delete).await?;
+            }
+        }
+
+        migrated += 1;
+        info!(image = image.id, from = from.name(), to = to.name(), "Migrated image");
+    }
+
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn filesystem_ranges_only_read_the_requested_bytes() {
+        let root = tempfile::tempdir().unwrap();
+        let store = FilesystemStore { root: root.path().to_path_buf() };
+        let key = BlobKey::Original(1);
+
+        assert_eq!(store.len(key).await.unwrap(), None);
+        assert_eq!(store.get_range(key, 0, 1).await.unwrap(), None);
+
+        store.put(key, b"0123456789", "image/png").await.unwrap();
+        assert_eq!(store.len(key).await.unwrap(), Some(10));
+        assert_eq!(store.get_range(key, 2, 4).await.unwrap(), Some(b"234".to_vec()));
+        assert_eq!(store.get_range(key, 9, 9).await.unwrap(), Some(b"9".to_vec()));
+    }
+
+    #[test]
+    fn variant_paths_round_trip() {
+        let key = BlobKey::Variant { image: 3, width: 320, format: "webp" };
+        let path = key.path();
+        let parsed = parse_variant(3, path.rsplit('/').next().unwrap()).unwrap();
+        assert_eq!(parsed.path(), path);
+    }
+}
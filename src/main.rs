@@ -1,21 +1,35 @@
 mod context;
 mod dataloaders;
 mod schema;
-mod prof;
 mod auth;
+mod cache;
+mod config;
+mod health;
+mod middleware;
+mod metrics;
+mod telemetry;
+mod errors;
+mod graphql;
+mod persisted_queries;
+mod query_limits;
 mod image;
+mod image_store;
+mod moderation;
 mod chat;
 mod explore;
 mod analytics;
 mod followers;
+mod posts;
+mod comments;
+mod feed;
 mod for_you;
 //mod time;
 
 use crate::context::{make_shared_context, SharedContext, RedisClient};
+use crate::config::Config;
 use crate::schema::*;
 use crate::auth::{Auth, auth_token};
 use crate::dataloaders::{Loaders, make_loaders};
-use crate::prof::*;
 use async_graphql::http::{playground_source, GQLRequest, GraphQLPlaygroundConfig};
 use async_graphql::{IntoQueryBuilder, QueryBuilder, QueryResponse, EmptySubscription, Schema};
 use hyper::http::status::*;
@@ -25,8 +39,12 @@ use hyper::{Body, Request, Response, Server};
 use std::convert::Infallible;
 use std::str;
 use std::sync::Arc;
-use std::time::{SystemTime, Duration};
-use log::{info};
+use std::time::{SystemTime, Duration, Instant};
+use tracing::{info, warn, error, debug, info_span, Instrument};
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
+use tokio::time::delay_for;
 use hyper::http::HeaderValue;
 
 
@@ -48,12 +66,6 @@ async fn index_playground(_req: Request<Body>) -> HTTPResponse {
     return ok_response(src);
 }
 
-#[derive(serde::Serialize)]
-struct QueryResponseJSON {
-    data: Option<serde_json::Value>,
-    errors: Option<serde_json::Value>,
-}
-
 /*
 fn fmt_graphql_err() {
     #[error("Parse error: {0}")]
@@ -71,119 +83,21 @@ fn fmt_graphql_err() {
 }
 */
 
-async fn index_image(shared: &SharedContext, req: Request<Body>) -> HTTPResponse {
-    let path = req.uri().path();
-    let id : i32 = match path["images/".len()+1..].parse() {
-        Ok(id) => id,
-        Err(e) => return HTTPResponse::Error(StatusCode::BAD_REQUEST, "Could not parse image id!".to_string())
-    };
-
-    let result = match sqlx::query!("SELECT highres FROM IMAGES WHERE id=$1", id)
-        .fetch_one(&shared.db)
-        .await {
-        Ok(result) => result.highres,
-        Err(e) => return HTTPResponse::Error(StatusCode::NOT_FOUND, "".to_string())
-    };
-
-    if let Some(result) = result {
-        info!("IMAGE SIZE {} kb", result.len() / 1024);
-
-        let mut resp = Response::new(Body::from(result));
-        resp.headers_mut().insert("Content-Type", HeaderValue::from_static("image/png"));
-
-        return HTTPResponse::Ok(resp);
-    } else {
-        return HTTPResponse::Error(StatusCode::NOT_FOUND, "".to_string());
-    }
-}
-
-async fn index_graphql(shared: Arc<SharedContext>, loaders: Arc<Loaders>, auth: Option<Auth>, req: Request<Body>) -> HTTPResponse {
-    let mut prof = Prof::new();
-
-    let full_body_bytes = match hyper::body::to_bytes(req.into_body()).await {
-        Ok(r) => r,
-        Err(e) => {
-            return HTTPResponse::Error(
-                StatusCode::BAD_REQUEST,
-                format!("Could not merge body {}", e),
-            )
-        }
-    };
-
-    prof.log("Read body request");
-
-    let gql_query = match str::from_utf8(&full_body_bytes) {
-        Ok(r) => r,
-        Err(_) => {
-            return HTTPResponse::Error(
-                StatusCode::BAD_REQUEST,
-                "Body is not utf8 compliant".to_string(),
-            )
-        }
-    };
-
-    info!("{} Query body", gql_query);
-
-    prof.log("Converted to utf8");
-
-    let gql_request: GQLRequest = match serde_json::from_str(&gql_query) {
-        Ok(r) => r,
-        Err(e) => {
-            return HTTPResponse::Error(
-                StatusCode::BAD_REQUEST,
-                format!("Could not parse json: {}", e),
-            )
-        }
-    };
-
-    prof.log("Parsed gql request to json");
-
-    let mut query: QueryBuilder = match gql_request.into_query_builder().await {
-        Ok(q) => q,
-        Err(e) => {
-            return HTTPResponse::Error(
-                StatusCode::BAD_REQUEST,
-                format!("Could not parse query: {}", e),
-            )
-        }
-    };
-
-    prof.log("Parsed gql");
-
-    query = query.data(shared.clone()).data(loaders.clone());
-    if let Some(auth) = auth {
-        query = query.data(auth);
+async fn route_and_auth(ctx: Arc<SharedContext>, loaders: Arc<Loaders>, req: Request<Body>) -> HTTPResponse {
+    //probes must not depend on authentication
+    match req.uri().path() {
+        "/healthz" => return HTTPResponse::Ok(health::healthz()),
+        "/readyz" => return match health::readyz(&ctx, &loaders).await {
+            Ok(resp) => HTTPResponse::Ok(resp),
+            Err(e) => HTTPResponse::Internal(e),
+        },
+        "/metrics" => return match ctx.metrics.render(&ctx) {
+            Ok(resp) => HTTPResponse::Ok(resp),
+            Err(e) => HTTPResponse::Internal(e),
+        },
+        _ => {}
     }
 
-    let resp: QueryResponse = match query.execute(&shared.schema).await {
-        Ok(r) => r,
-        Err(e) => {
-            let gql_err = async_graphql::http::GQLError(&e);
-            return match serde_json::to_string(&gql_err) {
-                Ok(err_json) => HTTPResponse::Error(StatusCode::BAD_REQUEST, err_json),
-                Err(_) => HTTPResponse::Internal("Error could not be serialized to json".to_string())
-            }
-        }
-    };
-
-    prof.log("Executed graphql query");
-
-    let resp_json_obj = QueryResponseJSON {
-        data: Some(resp.data),
-        errors: None,
-    };
-
-    let resp_json = match serde_json::to_string(&resp_json_obj) {
-        Ok(r) => r,
-        Err(e) => return HTTPResponse::Internal(format!("Could not serialize query {}", e)),
-    };
-
-    prof.log("Serialized response to json");
-
-    return ok_response(resp_json);
-}
-
-async fn route_and_auth(ctx: Arc<SharedContext>, loaders: Arc<Loaders>, req: Request<Body>) -> HTTPResponse {
     let bearer = req.headers().get("bearer");
     let auth = match bearer {
         Some(bearer) => {
@@ -192,7 +106,10 @@ async fn route_and_auth(ctx: Arc<SharedContext>, loaders: Arc<Loaders>, req: Req
                 Err(e) => return HTTPResponse::Error(StatusCode::BAD_REQUEST, "Bearer token must be string".to_string())
             };
 
-            let mut redis = ctx.redis.conn().await;
+            let mut redis = match ctx.redis.try_conn().await {
+                Ok(redis) => redis,
+                Err(e) => return HTTPResponse::Error(StatusCode::SERVICE_UNAVAILABLE, "Authentication is temporarily unavailable".to_string())
+            };
             match auth_token(&mut redis, as_str).await {
                 Ok(auth) => Some(auth),
                 Err(e) => return HTTPResponse::Error(StatusCode::BAD_REQUEST, "Failed to authenticate".to_string()) //todo not always the case
@@ -203,50 +120,85 @@ async fn route_and_auth(ctx: Arc<SharedContext>, loaders: Arc<Loaders>, req: Req
 
     let path = req.uri().path();
     match path {
-        "/graphql" => index_graphql(ctx, loaders,auth, req).await,
+        "/graphql" => graphql::index_graphql(ctx, loaders, auth, req).await,
         "/graphqi" => index_playground(req).await,
-        _ if path.starts_with("/images") => index_image(&ctx, req).await,
+        _ if path.starts_with("/images") => crate::image::index_image(&ctx, req).await,
         _ => HTTPResponse::Error(StatusCode::NOT_FOUND, format!("Could not find {}", path)),
     }
 }
 
+//the whole request, including reading the body, has to finish within the request timeout
+async fn handle(ctx: Arc<SharedContext>, loaders: Arc<Loaders>, req: Request<Body>) -> HTTPResponse {
+    let config = &ctx.config.http;
+    if middleware::is_preflight(&req) {
+        return middleware::preflight(config, &req);
+    }
+
+    //multipart bodies are streamed by the graphql handler, which enforces the file and body limits as it reads them
+    let deadline = tokio::time::Instant::now() + config.request_timeout();
+    let req = if middleware::is_multipart(&req) {
+        req
+    } else {
+        //a client that is slow to send its body gets 408, a handler that is too slow gets 504
+        match tokio::time::timeout_at(deadline, middleware::limit_body(config.max_body_bytes, req)).await {
+            Ok(Ok(req)) => req,
+            Ok(Err(e)) => return e,
+            Err(_) => return HTTPResponse::Error(StatusCode::REQUEST_TIMEOUT, "Request body was not received in time".to_string()),
+        }
+    };
+
+    match tokio::time::timeout_at(deadline, route_and_auth(ctx.clone(), loaders, req)).await {
+        Ok(resp) => resp,
+        Err(_) => HTTPResponse::Error(StatusCode::GATEWAY_TIMEOUT, "Request timed out".to_string()),
+    }
+}
+
 async fn index(ctx: Arc<SharedContext>, loaders: Arc<Loaders>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
     let time = SystemTime::now();
     let path = req.uri().path();
     let method = req.method().to_string();
     let owned = path.to_string();
+    let shared = ctx.clone();
 
-    match route_and_auth(ctx, loaders, req).await {
-        HTTPResponse::Ok(mut resp) => {
+    let request_id = telemetry::request_id(&req);
+    let span = info_span!("request", request_id = %request_id, method = %method, path = %owned);
+
+    let info = middleware::RequestInfo::new(&req);
+    let result = handle(ctx, loaders, req).instrument(span.clone()).await;
+    let enter = span.enter();
+
+    let mut resp = match result {
+        HTTPResponse::Ok(resp) => {
             let elapsed = time.elapsed().unwrap_or_else(|_| Duration::from_millis(0));
-            info!("{} [{}] - {}ms", method, StatusCode::OK, elapsed.as_millis());
+            info!(status = %resp.status(), elapsed_ms = elapsed.as_millis() as u64, "Request completed");
 
-            *resp.status_mut() = StatusCode::OK;
-            Ok(resp)
+            resp
         }
         HTTPResponse::Internal(err) => {
-            info!(
-                "{} [{}] {} {}",
-                method,
-                StatusCode::INTERNAL_SERVER_ERROR,
-                owned,
-                err,
-            );
+            error!(status = %StatusCode::INTERNAL_SERVER_ERROR, error = %err, "Request failed");
 
             let mut resp = Response::new(Body::from("Internal server error occured!"));
             *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
 
-            Ok(resp)
+            resp
         }
         HTTPResponse::Error(status, err) => {
-            info!("{} [{}] {} {}", method, status, owned, err);
+            info!(status = %status, error = %err, "Request rejected");
 
             let mut resp = Response::new(Body::from(err));
             *resp.status_mut() = status;
 
-            Ok(resp)
+            resp
         }
-    }
+    };
+
+    let elapsed = time.elapsed().unwrap_or_else(|_| Duration::from_millis(0));
+    shared.metrics.observe_request(&owned, resp.status(), elapsed);
+
+    resp.headers_mut().insert(telemetry::REQUEST_ID_HEADER, telemetry::request_id_header(&request_id));
+    drop(enter);
+
+    Ok(middleware::finish(&shared.config.http, &info, resp).await)
 }
 
 /*
@@ -268,14 +220,64 @@ impl Service<Request<Body>> for APIRouter {
     }
 }*/
 
+//fwave_backend migrate-images <from> <to> [--delete], where stores are postgres, filesystem or s3
+async fn migrate_images(config: Config, args: &[String]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let usage = "Usage: migrate-images <postgres|filesystem|s3> <postgres|filesystem|s3> [--delete]";
+    let (from, to) = match args {
+        [from, to, ..] => (from, to),
+        _ => return Err(usage.into()),
+    };
+
+    let from = from.parse().map_err(|_| usage)?;
+    let to = to.parse().map_err(|_| usage)?;
+    let delete = args.iter().any(|arg| arg == "--delete");
+    if from == to {
+        return Err("migrate-images needs two different stores".into());
+    }
+
+    let db = context::make_db(&config.database).await?;
+    let from = image_store::make_image_store(from, &config.images, &db)?;
+    let to = image_store::make_image_store(to, &config.images, &db)?;
+
+    let migrated = image_store::migrate(&db, from.as_ref(), to.as_ref(), delete).await?;
+    info!("Migrated {} images from {} to {}", migrated, from.name(), to.name());
+
+    Ok(())
+}
+
+//fwave_backend seed-persisted-queries <file>, where the file is a json array of query documents.
+//Run it before deploying clients when persisted_queries.allowlist is enabled
+async fn seed_persisted_queries(config: Config, args: &[String]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let path = args.first().ok_or("Usage: seed-persisted-queries <file>")?;
+    let queries: Vec<String> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+
+    let redis = context::make_redis(&config.redis).await?;
+    let seeded = persisted_queries::seed(&redis, &queries).await?;
+    info!("Seeded {} persisted queries", seeded);
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    pretty_env_logger::init();
+    let config = Config::load()?;
+    telemetry::init(&config.log)?;
 
-    let shared = make_shared_context().await?;
-    let loaders = make_loaders(shared.clone());
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(|arg| arg.as_str()) == Some("migrate-images") {
+        return migrate_images(config, &args[1..]).await;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("seed-persisted-queries") {
+        return seed_persisted_queries(config, &args[1..]).await;
+    }
+
+    let addr = config.server.bind_address;
+    let shutdown_timeout = config.server.shutdown_timeout();
 
-    let addr = ([0, 0, 0, 0], 8080).into();
+    let shared = make_shared_context(config).await?;
+    tokio::spawn(moderation::resume_pending(shared.clone()));
+    let loaders = make_loaders(shared.clone());
+    let shutdown_ctx = shared.clone();
 
     let service_fn = make_service_fn(move |_conn| {
         let shared = shared.clone();
@@ -294,11 +296,55 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     //{"action":{"Viewed":{"Project":10}},"timestamp":"2020-09-05T08:20:28.534821Z","duration":100}
     analytics::event_json_sample();
 
-    let server = Server::bind(&addr).serve(service_fn);
+    let (signalled_tx, signalled_rx) = oneshot::channel();
+    let signalled_at = Arc::new(std::sync::Mutex::new(None));
+    let signalled = signalled_at.clone();
+    let server = Server::bind(&addr)
+        .serve(service_fn)
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            *signalled.lock().unwrap() = Some(Instant::now());
+            let _ = signalled_tx.send(());
+        });
 
     info!("Listening on http://{}", addr);
 
-    server.await?;
+    //in-flight requests and the analytics drain share the shutdown timeout, counted from the signal
+    select! {
+        result = server => result?,
+        _ = async { let _ = signalled_rx.await; delay_for(shutdown_timeout).await } => {
+            warn!("In-flight requests did not finish within {}ms", shutdown_timeout.as_millis());
+        }
+    }
+
+    let elapsed = signalled_at.lock().unwrap().map_or(Duration::from_secs(0), |at: Instant| at.elapsed());
+    shutdown(&shutdown_ctx, shutdown_timeout.checked_sub(elapsed).unwrap_or_default()).await;
 
     Ok(())
 }
+
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            error!("Could not listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    select! {
+        _ = terminate.recv() => {},
+        _ = tokio::signal::ctrl_c() => {},
+    }
+
+    info!("Shutting down, no longer accepting connections");
+}
+
+async fn shutdown(shared: &SharedContext, timeout: Duration) {
+    shared.analytics.shutdown(timeout).await;
+    shared.db.close().await;
+    shared.redis.close();
+
+    info!("Shutdown complete");
+}
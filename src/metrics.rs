@@ -0,0 +1,172 @@
+use crate::context::SharedContext;
+use hyper::http::status::StatusCode;
+use hyper::{header, Body, Response};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
+//operation names are chosen by clients, only this many distinct ones get their own series
+const MAX_OPERATION_LABELS: usize = 100;
+const MAX_OPERATION_NAME_LEN: usize = 64;
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    graphql_duration: HistogramVec,
+    operations: Mutex<HashSet<String>>,
+    db_connections: IntGauge,
+    db_idle: IntGauge,
+    redis_idle: IntGauge,
+    redis_in_use: IntGauge,
+    redis_waiters: IntGauge,
+    redis_reconnecting: IntGauge,
+    analytics_queue_depth: IntGauge,
+}
+
+fn gauge(registry: &Registry, name: &str, help: &str) -> prometheus::Result<IntGauge> {
+    let gauge = IntGauge::new(name, help)?;
+    registry.register(Box::new(gauge.clone()))?;
+    Ok(gauge)
+}
+
+//keeps the route label bounded, image ids and unknown paths are not separate series
+pub fn route_label(path: &str) -> &'static str {
+    match path {
+        "/graphql" => "/graphql",
+        "/graphqi" => "/graphqi",
+        "/healthz" => "/healthz",
+        "/readyz" => "/readyz",
+        "/metrics" => "/metrics",
+        _ if path.starts_with("/images") => "/images",
+        _ => "other",
+    }
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Metrics> {
+        let registry = Registry::new();
+
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route and status"),
+            &["route", "status"],
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency by route and status"),
+            &["route", "status"],
+        )?;
+        registry.register(Box::new(request_duration.clone()))?;
+
+        let graphql_duration = HistogramVec::new(
+            HistogramOpts::new("graphql_operation_duration_seconds", "GraphQL execution time by operation name"),
+            &["operation"],
+        )?;
+        registry.register(Box::new(graphql_duration.clone()))?;
+
+        Ok(Metrics {
+            db_connections: gauge(&registry, "db_pool_connections", "Open Postgres connections")?,
+            db_idle: gauge(&registry, "db_pool_idle", "Idle Postgres connections")?,
+            redis_idle: gauge(&registry, "redis_pool_idle", "Idle Redis connections")?,
+            redis_in_use: gauge(&registry, "redis_pool_in_use", "Redis connections checked out")?,
+            redis_waiters: gauge(&registry, "redis_pool_waiters", "Tasks waiting for a Redis connection")?,
+            redis_reconnecting: gauge(&registry, "redis_pool_reconnecting", "Broken Redis connections being replaced")?,
+            analytics_queue_depth: gauge(&registry, "analytics_queue_depth", "Analytics tasks waiting for a worker")?,
+            registry,
+            requests,
+            request_duration,
+            graphql_duration,
+            operations: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn observe_request(&self, path: &str, status: StatusCode, elapsed: Duration) {
+        let labels = [route_label(path), status.as_str()];
+        self.requests.with_label_values(&labels).inc();
+        self.request_duration.with_label_values(&labels).observe(elapsed.as_secs_f64());
+    }
+
+    //new names are labelled "other" once MAX_OPERATION_LABELS distinct names have been seen
+    fn operation_label<'a>(&self, operation: Option<&'a str>) -> &'a str {
+        let operation = match operation {
+            Some(operation) => operation,
+            None => return "anonymous",
+        };
+
+        let valid = operation.len() <= MAX_OPERATION_NAME_LEN
+            && operation.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return "other";
+        }
+
+        let mut operations = self.operations.lock().unwrap();
+        if operations.contains(operation) {
+            return operation;
+        }
+        if operations.len() < MAX_OPERATION_LABELS {
+            operations.insert(operation.to_string());
+            return operation;
+        }
+        "other"
+    }
+
+    pub fn observe_graphql(&self, operation: Option<&str>, elapsed: Duration) {
+        self.graphql_duration
+            .with_label_values(&[self.operation_label(operation)])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    //pool and queue gauges are sampled when scraped
+    pub fn render(&self, shared: &SharedContext) -> Result<Response<Body>, String> {
+        self.db_connections.set(shared.db.size() as i64);
+        self.db_idle.set(shared.db.num_idle() as i64);
+
+        let redis = shared.redis.stats();
+        self.redis_idle.set(redis.idle as i64);
+        self.redis_in_use.set(redis.in_use as i64);
+        self.redis_waiters.set(redis.waiters as i64);
+        self.redis_reconnecting.set(redis.reconnecting as i64);
+
+        self.analytics_queue_depth.set(shared.analytics.queue_depth() as i64);
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| format!("Could not encode metrics {}", e))?;
+
+        Response::builder()
+            .header(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)
+            .body(Body::from(buffer))
+            .map_err(|e| format!("Could not build metrics response {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operation_labels_are_bounded() {
+        let metrics = Metrics::new().unwrap();
+
+        assert_eq!(metrics.operation_label(None), "anonymous");
+        assert_eq!(metrics.operation_label(Some("Feed")), "Feed");
+        assert_eq!(metrics.operation_label(Some("not a name")), "other");
+        assert_eq!(metrics.operation_label(Some(&"a".repeat(MAX_OPERATION_NAME_LEN + 1))), "other");
+
+        for i in 0..MAX_OPERATION_LABELS * 2 {
+            metrics.operation_label(Some(&format!("Op{}", i)));
+        }
+        assert_eq!(metrics.operations.lock().unwrap().len(), MAX_OPERATION_LABELS);
+        assert_eq!(metrics.operation_label(Some("Feed")), "Feed");
+        assert_eq!(metrics.operation_label(Some("Unseen")), "other");
+    }
+
+    #[test]
+    fn routes_are_bounded() {
+        assert_eq!(route_label("/images/12/640"), "/images");
+        assert_eq!(route_label("/wp-admin"), "other");
+    }
+}
@@ -0,0 +1,239 @@
+use crate::config::HttpConfig;
+use crate::HTTPResponse;
+use futures::StreamExt;
+use hyper::header::{self, HeaderMap};
+use hyper::http::status::StatusCode;
+use hyper::http::HeaderValue;
+use hyper::{Body, Method, Request, Response};
+use std::io::Write;
+
+const ALLOWED_METHODS: &str = "GET, POST, OPTIONS";
+const ALLOWED_HEADERS: &str = "content-type, bearer, x-request-id";
+const EXPOSED_HEADERS: &str = "x-request-id";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+//the parts of the request that are needed after it has been handed to the router
+pub struct RequestInfo {
+    origin: Option<HeaderValue>,
+    encoding: Option<Encoding>,
+}
+
+impl RequestInfo {
+    pub fn new(req: &Request<Body>) -> RequestInfo {
+        RequestInfo {
+            origin: req.headers().get(header::ORIGIN).cloned(),
+            encoding: req.headers().get(header::ACCEPT_ENCODING)
+                .and_then(|accept| accept.to_str().ok())
+                .and_then(negotiate_encoding),
+        }
+    }
+}
+
+//picks the encoding with the highest q value, brotli wins ties since it compresses better
+pub fn negotiate_encoding(accept: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .filter_map(|q| q.trim().parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+
+        let encoding = match name.as_str() {
+            "br" => Encoding::Brotli,
+            "gzip" | "*" => Encoding::Gzip,
+            _ => continue,
+        };
+
+        if q <= 0.0 {
+            continue;
+        }
+
+        let better = match best {
+            None => true,
+            Some((current, best_q)) => q > best_q || (q == best_q && encoding == Encoding::Brotli && current != Encoding::Brotli),
+        };
+
+        if better {
+            best = Some((encoding, q));
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+fn allowed_origin(config: &HttpConfig, origin: &HeaderValue) -> Option<HeaderValue> {
+    if config.cors_allowed_origins.iter().any(|allowed| allowed == "*") {
+        return Some(HeaderValue::from_static("*"));
+    }
+
+    let origin_str = origin.to_str().ok()?;
+    if config.cors_allowed_origins.iter().any(|allowed| allowed == origin_str) {
+        Some(origin.clone())
+    } else {
+        None
+    }
+}
+
+pub fn is_preflight(req: &Request<Body>) -> bool {
+    req.method() == Method::OPTIONS
+        && req.headers().contains_key(header::ORIGIN)
+        && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+//preflights are answered before authentication, browsers never send credentials with them
+pub fn preflight(config: &HttpConfig, req: &Request<Body>) -> HTTPResponse {
+    let origin = match req.headers().get(header::ORIGIN).and_then(|origin| allowed_origin(config, origin)) {
+        Some(origin) => origin,
+        None => return HTTPResponse::Error(StatusCode::FORBIDDEN, "Origin not allowed".to_string()),
+    };
+
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = StatusCode::NO_CONTENT;
+
+    let headers = resp.headers_mut();
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static(ALLOWED_METHODS));
+    headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static(ALLOWED_HEADERS));
+    headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(config.cors_max_age_secs));
+    headers.insert(header::VARY, HeaderValue::from_static("Origin"));
+
+    HTTPResponse::Ok(resp)
+}
+
+fn apply_cors(config: &HttpConfig, info: &RequestInfo, headers: &mut HeaderMap) {
+    let origin = match info.origin.as_ref().and_then(|origin| allowed_origin(config, origin)) {
+        Some(origin) => origin,
+        None => return,
+    };
+
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static(EXPOSED_HEADERS));
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+}
+
+pub fn is_multipart(req: &Request<Body>) -> bool {
+    req.headers().get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map_or(false, |content_type| content_type.starts_with("multipart/form-data"))
+}
+
+//buffers the body up to the limit, so handlers can read it without worrying about its size
+pub async fn limit_body(max_body_bytes: usize, req: Request<Body>) -> Result<Request<Body>, HTTPResponse> {
+    let too_large = || HTTPResponse::Error(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("Request body is limited to {} bytes", max_body_bytes),
+    );
+
+    let content_length = req.headers().get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok());
+
+    if content_length.map_or(false, |length| length > max_body_bytes) {
+        return Err(too_large());
+    }
+
+    let (parts, mut body) = req.into_parts();
+    let mut bytes = Vec::with_capacity(content_length.unwrap_or(0));
+
+    //chunked bodies have no content length, so the limit is also checked while reading
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| HTTPResponse::Error(StatusCode::BAD_REQUEST, format!("Could not read body {}", e)))?;
+        if bytes.len() + chunk.len() > max_body_bytes {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(Request::from_parts(parts, Body::from(bytes)))
+}
+
+fn is_compressible(headers: &HeaderMap) -> bool {
+    if headers.contains_key(header::CONTENT_ENCODING) {
+        return false;
+    }
+
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|content_type| content_type.to_str().ok());
+    match content_type {
+        //responses without a content type are plain text error messages or the playground
+        None => true,
+        Some(content_type) => content_type.starts_with("text/")
+            || content_type.starts_with("application/json")
+            || content_type.starts_with("application/javascript"),
+    }
+}
+
+fn compress(encoding: Encoding, bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(bytes)?;
+            encoder.finish()
+        }
+        Encoding::Brotli => {
+            let mut output = Vec::new();
+            {
+                let mut encoder = brotli::CompressorWriter::new(&mut output, 4096, 5, 22);
+                encoder.write_all(bytes)?;
+            }
+            Ok(output)
+        }
+    }
+}
+
+async fn compress_response(config: &HttpConfig, encoding: Encoding, resp: Response<Body>) -> Response<Body> {
+    if !is_compressible(resp.headers()) {
+        return resp;
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    let bytes = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(_) => return Response::from_parts(parts, Body::empty()),
+    };
+
+    parts.headers.append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+
+    //small bodies grow when compressed
+    if bytes.len() < config.compression_min_bytes {
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+
+    //compression is cpu bound, so it must not block the executor thread
+    let original = bytes.clone();
+    let compressed = tokio::task::spawn_blocking(move || compress(encoding, &bytes)).await;
+    match compressed {
+        Ok(Ok(compressed)) => {
+            parts.headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(compressed))
+        }
+        _ => Response::from_parts(parts, Body::from(original)),
+    }
+}
+
+pub async fn finish(config: &HttpConfig, info: &RequestInfo, mut resp: Response<Body>) -> Response<Body> {
+    apply_cors(config, info, resp.headers_mut());
+
+    match info.encoding {
+        Some(encoding) => compress_response(config, encoding, resp).await,
+        None => resp,
+    }
+}
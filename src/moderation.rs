@@ -0,0 +1,527 @@
+use tracing::Instrument;
+use crate::telemetry::sql_span;
+use crate::config::{ModerationBackend, ModerationConfig};
+use crate::context::SharedContext;
+use crate::image_store::BlobKey;
+use crate::schema::ImageID;
+use async_graphql_derive::*;
+use async_trait::async_trait;
+use sqlx::query;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+const ALLOWED_CONTENT_TYPES: [&str; 4] = ["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+#[Enum]
+pub enum ModerationStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+impl ModerationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModerationStatus::Pending => "pending",
+            ModerationStatus::Approved => "approved",
+            ModerationStatus::Rejected => "rejected",
+        }
+    }
+
+    //unknown values are treated as pending, so they are never served as approved
+    pub fn from_db(status: &str) -> ModerationStatus {
+        match status {
+            "approved" => ModerationStatus::Approved,
+            "rejected" => ModerationStatus::Rejected,
+            _ => ModerationStatus::Pending,
+        }
+    }
+}
+
+pub enum Verdict {
+    Approved,
+    Rejected(String),
+}
+
+pub struct ModerationRequest {
+    pub id: ImageID,
+    pub bytes: Vec<u8>,
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+//an error leaves the image pending, it is retried the next time the server starts
+#[async_trait]
+pub trait ModerationProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+    //false while the provider can not reach a verdict at all, pending images are then left alone
+    fn can_decide(&self) -> bool { true }
+    async fn moderate(&self, request: &ModerationRequest) -> Result<Verdict, String>;
+}
+
+pub struct RulesProvider {
+    config: ModerationConfig,
+    max_bytes: usize,
+}
+
+#[async_trait]
+impl ModerationProvider for RulesProvider {
+    fn name(&self) -> &'static str { "rules" }
+
+    async fn moderate(&self, request: &ModerationRequest) -> Result<Verdict, String> {
+        let config = &self.config;
+
+        if request.bytes.len() > self.max_bytes {
+            return Ok(Verdict::Rejected(format!("Image is larger than {} bytes", self.max_bytes)));
+        }
+
+        if !ALLOWED_CONTENT_TYPES.contains(&request.content_type.as_str()) {
+            return Ok(Verdict::Rejected(format!("Format {} is not allowed", request.content_type)));
+        }
+
+        if request.width < config.min_dimension || request.height < config.min_dimension {
+            return Ok(Verdict::Rejected(format!("Image is smaller than {}px", config.min_dimension)));
+        }
+
+        if request.width > config.max_dimension || request.height > config.max_dimension {
+            return Ok(Verdict::Rejected(format!("Image is larger than {}px", config.max_dimension)));
+        }
+
+        //metadata is stripped on upload, anything left means the file was crafted to get past the stripping
+        if has_metadata(&request.bytes) {
+            return Ok(Verdict::Rejected("Image still contains metadata".to_string()));
+        }
+
+        Ok(Verdict::Approved)
+    }
+}
+
+//placeholder for a hosted moderation service, until one is chosen images stay pending
+pub struct ExternalProvider {
+    url: String,
+}
+
+#[async_trait]
+impl ModerationProvider for ExternalProvider {
+    fn name(&self) -> &'static str { "external" }
+    fn can_decide(&self) -> bool { false }
+
+    async fn moderate(&self, request: &ModerationRequest) -> Result<Verdict, String> {
+        Err(format!("External moderation at {} is not implemented, image {} stays pending", self.url, request.id))
+    }
+}
+
+pub fn make_moderation_provider(config: &ModerationConfig, max_bytes: usize) -> Arc<dyn ModerationProvider> {
+    match config.provider {
+        ModerationBackend::Rules => Arc::new(RulesProvider { config: config.clone(), max_bytes }),
+        ModerationBackend::External => Arc::new(ExternalProvider { url: config.external_url.clone() }),
+    }
+}
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const ORIENTATION_TAG: u16 = 0x0112;
+
+//the orientation from the first IFD of an APP1 EXIF payload, 1 is the default and is not returned
+fn exif_orientation(payload: &[u8]) -> Option<u16> {
+    let tiff = payload.strip_prefix(EXIF_HEADER)?;
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+
+    let u16_at = |at: usize| -> Option<u16> {
+        let bytes = [*tiff.get(at)?, *tiff.get(at + 1)?];
+        Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    };
+    let u32_at = |at: usize| -> Option<u32> {
+        let bytes = [*tiff.get(at)?, *tiff.get(at + 1)?, *tiff.get(at + 2)?, *tiff.get(at + 3)?];
+        Some(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)? as usize;
+    (0..entries)
+        .map(|entry| ifd + 2 + entry * 12)
+        .find(|&entry| u16_at(entry) == Some(ORIENTATION_TAG))
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|orientation| (2..=8).contains(orientation))
+}
+
+//an APP1 segment with an EXIF block that only holds the orientation
+fn orientation_segment(orientation: u16) -> Vec<u8> {
+    let mut tiff = b"MM\0\x2A".to_vec();
+    tiff.extend_from_slice(&8u32.to_be_bytes());
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    tiff.extend_from_slice(&ORIENTATION_TAG.to_be_bytes());
+    tiff.extend_from_slice(&3u16.to_be_bytes()); //SHORT
+    tiff.extend_from_slice(&1u32.to_be_bytes());
+    tiff.extend_from_slice(&orientation.to_be_bytes());
+    tiff.extend_from_slice(&[0, 0]);
+    tiff.extend_from_slice(&0u32.to_be_bytes()); //no further IFDs
+
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&((2 + EXIF_HEADER.len() + tiff.len()) as u16).to_be_bytes());
+    segment.extend_from_slice(EXIF_HEADER);
+    segment.extend_from_slice(&tiff);
+    segment
+}
+
+//APP2 to APP15 and comments hold maker notes, Photoshop and IPTC data, which can contain names and
+//locations as well. Only the ICC profile in APP2 is kept, colours would be rendered wrong without it
+fn keep_jpeg_segment(marker: u8, payload: &[u8]) -> bool {
+    match marker {
+        0xE2 => payload.starts_with(b"ICC_PROFILE\0"),
+        0xE3..=0xEF | 0xFE => false,
+        _ => true,
+    }
+}
+
+//APP1 segments hold EXIF and XMP, both of which can contain GPS coordinates. Viewers rotate photos
+//according to the EXIF orientation, so it is kept in a fresh EXIF block without any other tags
+fn strip_jpeg(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(&bytes[..2]);
+    let mut orientation = None;
+
+    let mut i = 2;
+    while i + 4 <= bytes.len() {
+        if bytes[i] != 0xFF {
+            return None;
+        }
+
+        //markers can be preceded by any number of fill bytes
+        if bytes[i + 1] == 0xFF {
+            i += 1;
+            continue;
+        }
+
+        //start of scan, the compressed image data follows until the end of the file
+        let marker = bytes[i + 1];
+        if marker == 0xDA {
+            out.extend_from_slice(&bytes[i..]);
+            return Some(out);
+        }
+
+        let len = u16::from_be_bytes([bytes[i + 2], bytes[i + 3]]) as usize;
+        let end = i + 2 + len;
+        if len < 2 || end > bytes.len() {
+            return None;
+        }
+
+        if marker != 0xE1 {
+            if keep_jpeg_segment(marker, &bytes[i + 4..end]) {
+                out.extend_from_slice(&bytes[i..end]);
+            }
+        } else if orientation.is_none() {
+            //the first EXIF block with an orientation is replaced in place, every other APP1 segment is dropped
+            orientation = exif_orientation(&bytes[i + 4..end]);
+            if let Some(orientation) = orientation {
+                out.extend_from_slice(&orientation_segment(orientation));
+            }
+        }
+        i = end;
+    }
+
+    None
+}
+
+//eXIf holds EXIF, XMP is stored in the text chunks
+fn strip_png(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(&bytes[..8]);
+
+    let mut i = 8;
+    while i + 12 <= bytes.len() {
+        let len = u32::from_be_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]) as usize;
+        let end = i.checked_add(12 + len)?;
+        if end > bytes.len() {
+            return None;
+        }
+
+        let kind = &bytes[i + 4..i + 8];
+        if !matches!(kind, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt") {
+            out.extend_from_slice(&bytes[i..end]);
+        }
+
+        if kind == b"IEND" {
+            return Some(out);
+        }
+        i = end;
+    }
+
+    None
+}
+
+//the VP8X header flags which optional chunks are present, so the EXIF and XMP flags are cleared too
+fn strip_webp(bytes: &[u8]) -> Option<Vec<u8>> {
+    const EXIF_FLAG: u8 = 0x08;
+    const XMP_FLAG: u8 = 0x04;
+
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(&bytes[..12]);
+
+    let mut i = 12;
+    while i + 8 <= bytes.len() {
+        let len = u32::from_le_bytes([bytes[i + 4], bytes[i + 5], bytes[i + 6], bytes[i + 7]]) as usize;
+        let end = i.checked_add(8 + len + len % 2)?.min(bytes.len());
+        if i + 8 + len > bytes.len() {
+            return None;
+        }
+
+        let kind = &bytes[i..i + 4];
+        if kind == b"VP8X" && len > 0 {
+            let start = out.len();
+            out.extend_from_slice(&bytes[i..end]);
+            out[start + 8] &= !(EXIF_FLAG | XMP_FLAG);
+        } else if kind != b"EXIF" && kind != b"XMP " {
+            out.extend_from_slice(&bytes[i..end]);
+        }
+        i = end;
+    }
+
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(out)
+}
+
+//None means the file is malformed, gifs have no EXIF so they are returned unchanged
+pub fn strip_metadata(bytes: &[u8], content_type: &str) -> Option<Vec<u8>> {
+    match content_type {
+        "image/jpeg" => strip_jpeg(bytes),
+        "image/png" => strip_png(bytes),
+        "image/webp" => strip_webp(bytes),
+        _ => Some(bytes.to_vec()),
+    }
+}
+
+fn has_metadata(bytes: &[u8]) -> bool {
+    match crate::image::sniff_content_type(bytes) {
+        Some(content_type) => strip_metadata(bytes, content_type).map_or(true, |stripped| stripped.len() != bytes.len()),
+        None => true,
+    }
+}
+
+pub async fn moderate_image(shared: &SharedContext, request: ModerationRequest) {
+    let provider = &shared.moderation;
+    let verdict = match provider.moderate(&request).await {
+        Ok(verdict) => verdict,
+        Err(e) => {
+            warn!(image = request.id, provider = provider.name(), error = %e, "Could not moderate image");
+            return;
+        }
+    };
+
+    let (status, reason) = match verdict {
+        Verdict::Approved => (ModerationStatus::Approved, None),
+        Verdict::Rejected(reason) => (ModerationStatus::Rejected, Some(reason)),
+    };
+
+    info!(image = request.id, provider = provider.name(), status = status.as_str(), reason = ?reason, "Moderated image");
+
+    if let Err(e) = query!("UPDATE Images SET status = $2, rejectionreason = $3 WHERE id = $1 AND status = 'pending'",
+        request.id, status.as_str(), reason)
+        .execute(&shared.db)
+        .instrument(sql_span("moderation.moderate_image"))
+        .await {
+        error!(image = request.id, error = %e, "Could not store moderation result");
+    }
+}
+
+//images that were still pending when the server stopped are moderated again.
+//Images uploaded before their dimensions were recorded are measured from the stored bytes
+pub async fn resume_pending(shared: Arc<SharedContext>) {
+    if !shared.moderation.can_decide() {
+        info!(provider = shared.moderation.name(), "Moderation provider can not decide, pending images stay pending");
+        return;
+    }
+
+    let pending = match query!("SELECT id, contenttype, width, height FROM Images WHERE status = 'pending' ORDER BY id")
+        .fetch_all(&shared.db)
+        .instrument(sql_span("moderation.resume_pending"))
+        .await {
+        Ok(pending) => pending,
+        Err(e) => {
+            error!(error = %e, "Could not load pending images");
+            return;
+        }
+    };
+
+    for image in pending {
+        let bytes = match shared.images.get(BlobKey::Original(image.id)).await {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => continue,
+            Err(e) => {
+                error!(image = image.id, error = %e, "Could not load pending image");
+                continue;
+            }
+        };
+
+        let (width, height) = match (image.width, image.height) {
+            (Some(width), Some(height)) => (width as u32, height as u32),
+            _ => match crate::image::image_dimensions(&bytes) {
+                Ok(dimensions) => dimensions,
+                Err(e) => {
+                    warn!(image = image.id, error = %e, "Could not read dimensions of pending image");
+                    continue;
+                }
+            },
+        };
+
+        let content_type = image.contenttype
+            .or_else(|| crate::image::sniff_content_type(&bytes).map(|content_type| content_type.to_string()))
+            .unwrap_or_default();
+
+        moderate_image(&shared, ModerationRequest { id: image.id, bytes, content_type, width, height }).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    //a little endian EXIF block with the orientation and a GPS IFD pointer
+    fn exif(orientation: u16) -> Vec<u8> {
+        let mut payload = EXIF_HEADER.to_vec();
+        payload.extend_from_slice(b"II\x2A\0");
+        payload.extend_from_slice(&8u32.to_le_bytes());
+        payload.extend_from_slice(&2u16.to_le_bytes());
+        payload.extend_from_slice(&ORIENTATION_TAG.to_le_bytes());
+        payload.extend_from_slice(&3u16.to_le_bytes());
+        payload.extend_from_slice(&1u32.to_le_bytes());
+        payload.extend_from_slice(&orientation.to_le_bytes());
+        payload.extend_from_slice(&[0, 0]);
+        payload.extend_from_slice(&0x8825u16.to_le_bytes());
+        payload.extend_from_slice(&4u16.to_le_bytes());
+        payload.extend_from_slice(&1u32.to_le_bytes());
+        payload.extend_from_slice(&38u32.to_le_bytes());
+        payload.extend_from_slice(&0u32.to_le_bytes());
+        payload.extend_from_slice(b"GPS DATA");
+        payload
+    }
+
+    fn jpeg(segments: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = vec![0xFF, 0xD8];
+        for segment in segments {
+            bytes.extend_from_slice(segment);
+        }
+        bytes.extend_from_slice(&segment(0xDA, &[1, 2, 3]));
+        bytes.extend_from_slice(&[0x12, 0x34, 0xFF, 0xD9]);
+        bytes
+    }
+
+    #[test]
+    fn strips_jpeg_exif_and_xmp() {
+        let app0 = segment(0xE0, b"JFIF\0\x01\x01");
+        let xmp = segment(0xE1, b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>");
+        let stripped = strip_metadata(&jpeg(&[app0.clone(), segment(0xE1, &exif(1)), xmp]), "image/jpeg").unwrap();
+
+        assert_eq!(stripped, jpeg(&[app0]));
+        assert!(!has_metadata(&stripped));
+    }
+
+    #[test]
+    fn strips_jpeg_app_segments_and_comments_but_keeps_icc() {
+        let app0 = segment(0xE0, b"JFIF\0\x01\x01");
+        let icc = segment(0xE2, b"ICC_PROFILE\0\x01\x01profile");
+        let mpf = segment(0xE2, b"MPF\0preview");
+        let iptc = segment(0xED, b"Photoshop 3.0\08BIM location");
+        let comment = segment(0xFE, b"taken at home");
+        let stripped = strip_metadata(&jpeg(&[app0.clone(), icc.clone(), mpf, iptc, comment]), "image/jpeg").unwrap();
+
+        assert_eq!(stripped, jpeg(&[app0, icc]));
+        assert!(!has_metadata(&stripped));
+    }
+
+    #[test]
+    fn keeps_jpeg_orientation() {
+        let stripped = strip_metadata(&jpeg(&[segment(0xE1, &exif(6))]), "image/jpeg").unwrap();
+
+        assert_eq!(stripped, jpeg(&[orientation_segment(6)]));
+        assert_eq!(exif_orientation(&stripped[6..6 + EXIF_HEADER.len() + 26]), Some(6));
+        assert!(!stripped.windows(8).any(|window| window == b"GPS DATA"));
+        assert!(!has_metadata(&stripped));
+    }
+
+    #[test]
+    fn rejects_truncated_jpeg() {
+        let mut bytes = jpeg(&[segment(0xE1, &exif(1))]);
+        bytes.truncate(10);
+        assert!(strip_metadata(&bytes, "image/jpeg").is_none());
+    }
+
+    fn chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&[0, 0, 0, 0]); //crc, not checked
+        chunk
+    }
+
+    fn png(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = b"\x89PNG\r\n\x1a\n".to_vec();
+        bytes.extend_from_slice(&chunk(b"IHDR", &[0; 13]));
+        for chunk in chunks {
+            bytes.extend_from_slice(chunk);
+        }
+        bytes.extend_from_slice(&chunk(b"IDAT", &[1, 2, 3]));
+        bytes.extend_from_slice(&chunk(b"IEND", &[]));
+        bytes
+    }
+
+    #[test]
+    fn strips_png_text_and_exif_chunks() {
+        let bytes = png(&[chunk(b"tEXt", b"Author\0me"), chunk(b"eXIf", &exif(1)[6..]), chunk(b"iTXt", b"XML:com.adobe.xmp\0")]);
+        let stripped = strip_metadata(&bytes, "image/png").unwrap();
+
+        assert_eq!(stripped, png(&[]));
+        assert!(!has_metadata(&stripped));
+    }
+
+    fn riff(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = kind.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn webp(flags: u8, chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = b"WEBP".to_vec();
+        body.extend_from_slice(&riff(b"VP8X", &[flags, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+        body.extend_from_slice(&riff(b"VP8 ", &[1, 2, 3]));
+        for chunk in chunks {
+            body.extend_from_slice(chunk);
+        }
+
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    #[test]
+    fn strips_webp_exif_and_xmp_chunks() {
+        let bytes = webp(0x08 | 0x04 | 0x10, &[riff(b"EXIF", &exif(1)[6..]), riff(b"XMP ", b"<x:xmpmeta/>")]);
+        let stripped = strip_metadata(&bytes, "image/webp").unwrap();
+
+        assert_eq!(stripped, webp(0x10, &[]));
+        assert!(!has_metadata(&stripped));
+    }
+
+    #[test]
+    fn gifs_are_unchanged() {
+        let bytes = b"GIF89a\x01\0\x01\0".to_vec();
+        assert_eq!(strip_metadata(&bytes, "image/gif"), Some(bytes));
+    }
+}
@@ -0,0 +1,217 @@
+use crate::config::PersistedQueryConfig;
+use crate::context::RedisClient;
+use crate::errors::ErrorCode;
+use redis::AsyncCommands;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use tracing::error;
+
+//the hash is set when the query should be registered, which only happens once it parsed,
+//validated and passed the query limits, so arbitrary text can not be stored under a hash
+pub struct ResolvedQuery {
+    pub query: String,
+    pub register: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PersistedQuery {
+    pub version: u32,
+    #[serde(rename = "sha256Hash")]
+    pub sha256_hash: String,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct RequestExtensions {
+    #[serde(rename = "persistedQuery")]
+    pub persisted_query: Option<PersistedQuery>,
+}
+
+pub enum PersistedQueryError {
+    NotFound,
+    NotAllowed,
+    HashMismatch,
+    UnsupportedVersion,
+    Missing,
+    Redis(redis::RedisError),
+}
+
+impl PersistedQueryError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            PersistedQueryError::NotFound => ErrorCode::PersistedQueryNotFound,
+            PersistedQueryError::NotAllowed => ErrorCode::PersistedQueryNotAllowed,
+            PersistedQueryError::Redis(_) => ErrorCode::Internal,
+            _ => ErrorCode::Validation,
+        }
+    }
+
+    //clients retry with the full query when they see PersistedQueryNotFound, so the message must match
+    pub fn message(&self) -> &'static str {
+        match self {
+            PersistedQueryError::NotFound => "PersistedQueryNotFound",
+            PersistedQueryError::NotAllowed => "PersistedQueryNotAllowed",
+            PersistedQueryError::HashMismatch => "provided sha does not match query",
+            PersistedQueryError::UnsupportedVersion => "Unsupported persisted query version",
+            PersistedQueryError::Missing => "Request must contain a query or a persisted query hash",
+            PersistedQueryError::Redis(_) => "Internal server error",
+        }
+    }
+}
+
+//seeded queries live in their own namespace, so a query a client registered can never pass the allowlist
+fn persisted_query_key(hash: &str) -> String { format!("persisted_query:{}", hash) }
+fn allowlisted_query_key(hash: &str) -> String { format!("persisted_query:allow:{}", hash) }
+
+//seeded queries can also be sent by hash when the allowlist is off
+fn lookup_keys(allowlist: bool, hash: &str) -> Vec<String> {
+    if allowlist {
+        vec![allowlisted_query_key(hash)]
+    } else {
+        vec![persisted_query_key(hash), allowlisted_query_key(hash)]
+    }
+}
+
+pub fn sha256_hex(query: &str) -> String {
+    hex::encode(Sha256::digest(query.as_bytes()))
+}
+
+async fn lookup(redis: &RedisClient, keys: Vec<String>) -> redis::RedisResult<Option<String>> {
+    let mut conn = redis.try_conn().await?;
+    for key in keys {
+        if let Some(query) = conn.get(key).await? {
+            return Ok(Some(query));
+        }
+    }
+
+    Ok(None)
+}
+
+//the client sent the full query, so failing to register only costs a retransmission later
+pub async fn register(redis: &RedisClient, config: &PersistedQueryConfig, hash: &str, query: &str) {
+    if query.len() > config.max_query_bytes {
+        return;
+    }
+
+    let register = async {
+        let mut conn = redis.try_conn().await?;
+        redis::cmd("SET").arg(persisted_query_key(hash)).arg(query).arg("NX").arg("EX").arg(config.ttl_secs)
+            .query_async::<_, ()>(&mut conn).await
+    };
+
+    if let Err(e) = register.await {
+        error!(error = %e, "Could not register persisted query");
+    }
+}
+
+//seeded queries never expire, this is how the allowlist is filled before a release
+pub async fn seed(redis: &RedisClient, queries: &[String]) -> redis::RedisResult<usize> {
+    let mut conn = redis.try_conn().await?;
+    for query in queries {
+        conn.set::<_, _, ()>(allowlisted_query_key(&sha256_hex(query)), query).await?;
+    }
+
+    Ok(queries.len())
+}
+
+//returns the query text to execute. In allowlist mode only queries that were registered ahead of time can run
+pub async fn resolve(redis: &RedisClient, allowlist: bool, query: Option<String>, extensions: &RequestExtensions) -> Result<ResolvedQuery, PersistedQueryError> {
+    resolve_with(|keys| lookup(redis, keys), allowlist, query, extensions).await
+}
+
+async fn resolve_with<L, Fut>(lookup: L, allowlist: bool, query: Option<String>, extensions: &RequestExtensions) -> Result<ResolvedQuery, PersistedQueryError>
+    where L: Fn(Vec<String>) -> Fut,
+          Fut: Future<Output = redis::RedisResult<Option<String>>>,
+{
+    let lookup = |hash: &str| {
+        let found = lookup(lookup_keys(allowlist, hash));
+        async move {
+            found.await.map_err(|e| {
+                error!(error = %e, "Could not look up persisted query");
+                PersistedQueryError::Redis(e)
+            })
+        }
+    };
+
+    let persisted = match &extensions.persisted_query {
+        Some(persisted) => persisted,
+        None => {
+            let query = query.ok_or(PersistedQueryError::Missing)?;
+            if allowlist && lookup(&sha256_hex(&query)).await?.is_none() {
+                return Err(PersistedQueryError::NotAllowed);
+            }
+            return Ok(ResolvedQuery { query, register: None });
+        }
+    };
+
+    if persisted.version != 1 {
+        return Err(PersistedQueryError::UnsupportedVersion);
+    }
+
+    let hash = persisted.sha256_hash.to_lowercase();
+
+    match query {
+        None => {
+            let query = lookup(&hash).await?.ok_or(PersistedQueryError::NotFound)?;
+            Ok(ResolvedQuery { query, register: None })
+        }
+        Some(query) => {
+            if sha256_hex(&query) != hash {
+                return Err(PersistedQueryError::HashMismatch);
+            }
+
+            if allowlist {
+                return match lookup(&hash).await? {
+                    Some(_) => Ok(ResolvedQuery { query, register: None }),
+                    None => Err(PersistedQueryError::NotAllowed),
+                };
+            }
+
+            Ok(ResolvedQuery { query, register: Some(hash) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const QUERY: &str = "{ feed(cursor: 0, limit: 10) { id } }";
+
+    async fn resolve_in(store: &HashMap<String, String>, allowlist: bool, query: Option<String>, hash: Option<String>) -> Result<ResolvedQuery, PersistedQueryError> {
+        let extensions = RequestExtensions {
+            persisted_query: hash.map(|sha256_hash| PersistedQuery { version: 1, sha256_hash }),
+        };
+
+        let lookup = |keys: Vec<String>| {
+            let found = keys.iter().find_map(|key| store.get(key).cloned());
+            async move { Ok::<_, redis::RedisError>(found) }
+        };
+
+        resolve_with(lookup, allowlist, query, &extensions).await
+    }
+
+    #[tokio::test]
+    async fn auto_registered_hash_is_rejected_in_allowlist_mode() {
+        let hash = sha256_hex(QUERY);
+        let mut store = HashMap::new();
+        store.insert(persisted_query_key(&hash), QUERY.to_string());
+
+        assert!(matches!(resolve_in(&store, true, None, Some(hash.clone())).await, Err(PersistedQueryError::NotFound)));
+        assert!(matches!(resolve_in(&store, true, Some(QUERY.to_string()), Some(hash.clone())).await, Err(PersistedQueryError::NotAllowed)));
+        assert!(matches!(resolve_in(&store, true, Some(QUERY.to_string()), None).await, Err(PersistedQueryError::NotAllowed)));
+        assert!(resolve_in(&store, false, None, Some(hash)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn seeded_hash_is_accepted_in_both_modes() {
+        let hash = sha256_hex(QUERY);
+        let mut store = HashMap::new();
+        store.insert(allowlisted_query_key(&hash), QUERY.to_string());
+
+        assert_eq!(resolve_in(&store, true, None, Some(hash.clone())).await.ok().unwrap().query, QUERY);
+        assert!(resolve_in(&store, true, Some(QUERY.to_string()), None).await.is_ok());
+        assert_eq!(resolve_in(&store, false, None, Some(hash)).await.ok().unwrap().query, QUERY);
+    }
+}
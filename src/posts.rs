@@ -0,0 +1,186 @@
+use tracing::Instrument;
+use crate::telemetry::sql_span;
+use crate::context::{get_db, get_redis, DBClient};
+use crate::cache::invalidate;
+use crate::auth::get_auth;
+use crate::errors;
+use crate::moderation::ModerationStatus;
+use crate::schema::{Post, ImageID};
+use async_graphql::{Context, FieldResult};
+use async_graphql_derive::*;
+use data::dataloader::ID;
+use chrono::Utc;
+use sqlx::{query, query_as};
+
+//ProjectMembers.role of members who can moderate every post of their project
+pub const PROJECT_ADMIN_ROLE: i32 = 1;
+
+pub const POST_EXPIRY: usize = 60;
+const MAX_TITLE_LEN: usize = 100;
+const MAX_DESCRIPTION_LEN: usize = 5000;
+
+pub fn post_key(id: ID) -> String { format!("post:{}", id) }
+
+fn validate_text(title: Option<&str>, description: Option<&str>) -> FieldResult<()> {
+    if let Some(title) = title {
+        if title.trim().is_empty() { return Err(errors::validation("Title must not be empty")) }
+        if title.chars().count() > MAX_TITLE_LEN { return Err(errors::validation(&format!("Title is limited to {} characters", MAX_TITLE_LEN))) }
+    }
+
+    if let Some(description) = description {
+        if description.chars().count() > MAX_DESCRIPTION_LEN { return Err(errors::validation(&format!("Description is limited to {} characters", MAX_DESCRIPTION_LEN))) }
+    }
+
+    Ok(())
+}
+
+//only approved images uploaded by the author can be published
+async fn check_image(db: &DBClient, user: ID, image: ImageID) -> FieldResult<()> {
+    let image = query!("SELECT account, status FROM Images WHERE id = $1", image)
+        .fetch_optional(db)
+        .instrument(sql_span("posts.check_image"))
+        .await?
+        .filter(|image| image.account == Some(user))
+        .ok_or_else(|| errors::not_found("No such image"))?;
+
+    match ModerationStatus::from_db(&image.status) {
+        ModerationStatus::Approved => Ok(()),
+        ModerationStatus::Pending => Err(errors::validation("The image is still being moderated")),
+        ModerationStatus::Rejected => Err(errors::validation("The image was rejected by moderation")),
+    }
+}
+
+async fn is_project_member(db: &DBClient, user: ID, project: ID, role: Option<i32>) -> FieldResult<bool> {
+    let member = query!("SELECT COUNT(*) FROM ProjectMembers WHERE project = $1 AND account = $2 AND ($3::int IS NULL OR role = $3)",
+        project, user, role)
+        .fetch_one(db)
+        .instrument(sql_span("posts.is_project_member"))
+        .await?;
+
+    Ok(member.count.unwrap_or(0) > 0)
+}
+
+//posts can be changed by their author or by an admin of the project they were posted in, returns the author
+async fn check_can_edit(db: &DBClient, user: ID, post: ID) -> FieldResult<ID> {
+    let post = query!("SELECT account, project FROM Posts WHERE id = $1", post)
+        .fetch_optional(db)
+        .instrument(sql_span("posts.check_can_edit"))
+        .await?
+        .ok_or_else(|| errors::not_found("No such post"))?;
+
+    if post.account == user || is_project_member(db, user, post.project, Some(PROJECT_ADMIN_ROLE)).await? {
+        Ok(post.account)
+    } else {
+        Err(errors::forbidden("Only the author or a project admin can change this post"))
+    }
+}
+
+async fn load_post(db: &DBClient, id: ID) -> FieldResult<Post> {
+    query_as!(Post, "SELECT id, account, image, title, description FROM Posts WHERE id = $1", id)
+        .fetch_optional(db)
+        .instrument(sql_span("posts.load_post"))
+        .await?
+        .ok_or_else(|| errors::not_found("No such post"))
+}
+
+#[derive(Default)]
+pub struct MutationPosts;
+
+#[Object]
+impl MutationPosts {
+    async fn create_post(&self, ctx: &Context<'_>, project: ID, title: String, description: String, image: ImageID) -> FieldResult<Post> {
+        let auth = get_auth(ctx)?;
+        let db = get_db(ctx);
+
+        validate_text(Some(&title), Some(&description))?;
+
+        if !is_project_member(db, auth.user, project, None).await? {
+            return Err(errors::forbidden("Only project members can post in a project"));
+        }
+        check_image(db, auth.user, image).await?;
+
+        //posted comes from the database clock, the feed ranks posts by their age against the same clock
+        let post = query_as!(Post, "INSERT INTO Posts (account, project, image, title, description, posted)
+        VALUES ($1, $2, $3, $4, $5, now())
+        RETURNING id, account, image, title, description", auth.user, project, image, title, description)
+            .fetch_one(db)
+            .instrument(sql_span("posts.create_post"))
+            .await?;
+
+        //a lookup of the id before the post existed may still be cached. The feed is read from the
+        //database on every request, so only the post key has to be invalidated here and below
+        invalidate(get_redis(ctx), &[post_key(post.id)]).await;
+        Ok(post)
+    }
+
+    //fields that are left out keep their current value
+    async fn update_post(&self, ctx: &Context<'_>, id: ID, title: Option<String>, description: Option<String>, image: Option<ImageID>) -> FieldResult<Post> {
+        let auth = get_auth(ctx)?;
+        let db = get_db(ctx);
+
+        validate_text(title.as_deref(), description.as_deref())?;
+        //an admin editing the post can only set images the author uploaded, not their own
+        let author = check_can_edit(db, auth.user, id).await?;
+        if let Some(image) = image {
+            check_image(db, author, image).await?;
+        }
+
+        let post = query_as!(Post, "UPDATE Posts
+        SET title = COALESCE($2, title), description = COALESCE($3, description), image = COALESCE($4, image)
+        WHERE id = $1
+        RETURNING id, account, image, title, description", id, title, description, image)
+            .fetch_one(db)
+            .instrument(sql_span("posts.update_post"))
+            .await?;
+
+        invalidate(get_redis(ctx), &[post_key(id)]).await;
+        Ok(post)
+    }
+
+    async fn delete_post(&self, ctx: &Context<'_>, id: ID) -> FieldResult<ID> {
+        let auth = get_auth(ctx)?;
+        let db = get_db(ctx);
+
+        check_can_edit(db, auth.user, id).await?;
+
+        let mut tx = db.begin().await?;
+        query!("DELETE FROM CommentReports WHERE comment IN (SELECT id FROM Comments WHERE post = $1)", id).execute(&mut tx).instrument(sql_span("posts.delete_post")).await?;
+        query!("DELETE FROM Comments WHERE post = $1", id).execute(&mut tx).instrument(sql_span("posts.delete_post")).await?;
+        query!("DELETE FROM PostLikes WHERE post = $1", id).execute(&mut tx).instrument(sql_span("posts.delete_post")).await?;
+        query!("DELETE FROM Posts WHERE id = $1", id).execute(&mut tx).instrument(sql_span("posts.delete_post")).await?;
+        tx.commit().await?;
+
+        invalidate(get_redis(ctx), &[post_key(id)]).await;
+        Ok(id)
+    }
+
+    //liking twice is a no op, the post is returned so clients can refresh likes and likedByMe.
+    //PostLikes has a unique key on (post, account), so concurrent likes can not insert duplicates
+    async fn like_post(&self, ctx: &Context<'_>, post: ID) -> FieldResult<Post> {
+        let auth = get_auth(ctx)?;
+        let db = get_db(ctx);
+        let liked = Utc::now();
+
+        let post = load_post(db, post).await?;
+        query!("INSERT INTO PostLikes (post, account, liked) VALUES ($1, $2, $3)
+        ON CONFLICT (post, account) DO NOTHING", post.id, auth.user, liked)
+            .execute(db)
+            .instrument(sql_span("posts.like_post"))
+            .await?;
+
+        Ok(post)
+    }
+
+    async fn unlike_post(&self, ctx: &Context<'_>, post: ID) -> FieldResult<Post> {
+        let auth = get_auth(ctx)?;
+        let db = get_db(ctx);
+
+        let post = load_post(db, post).await?;
+        query!("DELETE FROM PostLikes WHERE post = $1 AND account = $2", post.id, auth.user)
+            .execute(db)
+            .instrument(sql_span("posts.unlike_post"))
+            .await?;
+
+        Ok(post)
+    }
+}
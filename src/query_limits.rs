@@ -0,0 +1,290 @@
+use crate::config::QueryLimitsConfig;
+use async_graphql_parser::query::{Definition, Document, FragmentDefinition, Name, Selection, SelectionSet, Value};
+use async_graphql_parser::Positioned;
+use data::sql_resolve::MAX_PAGE_LIMIT;
+use std::collections::{HashMap, HashSet};
+
+//Cost of a field on its own, list fields multiply the cost of their children by the number of items requested
+enum FieldCost {
+    Scalar(usize),
+    Object(usize),
+    List { base: usize, limit_arg: Option<&'static str> },
+}
+
+//fields that hit the database or return lists, every other field costs 1
+fn field_cost(name: &str) -> Option<FieldCost> {
+    Some(match name {
+        "account" | "myAccount" | "post" | "projectById" | "bondById" | "dm" | "group" => FieldCost::Object(2),
+        "likes" | "likedByMe" | "commentCount" | "replyCount" | "memberCount" | "followerCount" | "followingCount" => FieldCost::Scalar(2),
+        "posts" | "comments" | "feed" | "followers" | "likers" | "replies" | "reportedComments" => FieldCost::List { base: 2, limit_arg: Some("limit") },
+        "members" | "messages" | "chats" | "following" | "findAccounts" | "search" | "notableMembers" => FieldCost::List { base: 2, limit_arg: None },
+        "trending" | "topInvestments" | "categoriesForYou" => FieldCost::List { base: 2, limit_arg: Some("limit") },
+        _ => return None,
+    })
+}
+
+//the standard introspection query nests type references about 13 levels deep, which is more than
+//max_depth allows for regular queries, so introspection subtrees are measured against their own cap
+pub const MAX_INTROSPECTION_DEPTH: usize = 20;
+
+pub struct QueryCost {
+    pub depth: usize,
+    pub introspection_depth: usize,
+    pub complexity: usize,
+}
+
+struct Walker<'a> {
+    config: &'a QueryLimitsConfig,
+    fragments: HashMap<&'a str, &'a FragmentDefinition>,
+    variables: &'a Option<serde_json::Value>,
+    visiting: HashSet<&'a str>,
+}
+
+impl<'a> Walker<'a> {
+    fn list_size(&self, arguments: &[(Positioned<Name>, Positioned<Value>)], limit_arg: Option<&str>) -> usize {
+        let limit_arg = match limit_arg {
+            Some(limit_arg) => limit_arg,
+            None => return self.config.default_list_size,
+        };
+
+        let value = arguments.iter().find(|(name, _)| name.node.as_str() == limit_arg).map(|(_, value)| &value.node);
+        let size = match value {
+            Some(Value::Int(limit)) => Some(*limit),
+            Some(Value::Variable(variable)) => self.variables.as_ref()
+                .and_then(|variables| variables.get(variable.as_str()))
+                .and_then(|value| value.as_i64()),
+            _ => None,
+        };
+
+        //resolvers never return more than a page, however large the requested limit
+        size.map_or(self.config.default_list_size, |size| size.max(0).min(MAX_PAGE_LIMIT) as usize)
+    }
+
+    fn selection_set(&mut self, selection_set: &'a SelectionSet, depth: usize) -> QueryCost {
+        let mut cost = QueryCost { depth, introspection_depth: 0, complexity: 0 };
+
+        for selection in &selection_set.items {
+            let child = match &selection.node {
+                Selection::Field(field) => {
+                    let field = &field.node;
+                    let name = field.name.node.as_str();
+
+                    let children = self.selection_set(&field.selection_set.node, depth + 1);
+
+                    //introspection is free and only counts towards the introspection depth
+                    if name.starts_with("__") {
+                        let introspection_depth = children.depth.max(children.introspection_depth);
+                        QueryCost { depth, introspection_depth, complexity: 0 }
+                    } else {
+                        let complexity = match field_cost(name) {
+                            None => children.complexity.saturating_add(1),
+                            Some(FieldCost::Scalar(base)) | Some(FieldCost::Object(base)) => children.complexity.saturating_add(base),
+                            Some(FieldCost::List { base, limit_arg }) => self.list_size(&field.arguments, limit_arg)
+                                .saturating_mul(children.complexity.max(1))
+                                .saturating_add(base),
+                        };
+
+                        QueryCost { depth: children.depth, introspection_depth: children.introspection_depth, complexity }
+                    }
+                }
+                Selection::InlineFragment(fragment) => self.selection_set(&fragment.node.selection_set.node, depth),
+                Selection::FragmentSpread(spread) => {
+                    let name = spread.node.fragment_name.node.as_str();
+                    let fragment = match self.fragments.get(name) {
+                        Some(fragment) => *fragment,
+                        None => continue,
+                    };
+
+                    //cyclic fragments are rejected by validation, this only avoids looping until then
+                    if !self.visiting.insert(name) {
+                        continue;
+                    }
+                    let cost = self.selection_set(&fragment.selection_set.node, depth);
+                    self.visiting.remove(name);
+                    cost
+                }
+            };
+
+            cost.depth = cost.depth.max(child.depth);
+            cost.introspection_depth = cost.introspection_depth.max(child.introspection_depth);
+            cost.complexity = cost.complexity.saturating_add(child.complexity);
+        }
+
+        cost
+    }
+}
+
+pub fn query_cost(config: &QueryLimitsConfig, document: &Document, operation_name: &Option<String>, variables: &Option<serde_json::Value>) -> QueryCost {
+    let mut fragments = HashMap::new();
+    let mut operations = Vec::new();
+
+    for definition in &document.definitions {
+        match &definition.node {
+            Definition::Fragment(fragment) => { fragments.insert(fragment.node.name.node.as_str(), &fragment.node); }
+            Definition::Operation(operation) => operations.push(&operation.node),
+        }
+    }
+
+    let operation = match operation_name {
+        Some(name) => operations.into_iter().find(|operation| operation.name.as_ref().map_or(false, |n| n.node.as_str() == name)),
+        None => operations.into_iter().next(),
+    };
+
+    let mut walker = Walker { config, fragments, variables, visiting: HashSet::new() };
+    match operation {
+        Some(operation) => walker.selection_set(&operation.selection_set.node, 0),
+        None => QueryCost { depth: 0, introspection_depth: 0, complexity: 0 },
+    }
+}
+
+pub fn check_limits(config: &QueryLimitsConfig, query: &str, operation_name: &Option<String>, variables: &Option<serde_json::Value>) -> Result<(), String> {
+    let document = async_graphql_parser::parse_query(query)
+        .map_err(|e| format!("Could not parse query: {}", e))?;
+
+    let cost = query_cost(config, &document, operation_name, variables);
+
+    if cost.depth > config.max_depth {
+        return Err(format!("Query depth {} exceeds the limit of {}", cost.depth, config.max_depth));
+    }
+
+    if cost.introspection_depth > MAX_INTROSPECTION_DEPTH {
+        return Err(format!("Introspection depth {} exceeds the limit of {}", cost.introspection_depth, MAX_INTROSPECTION_DEPTH));
+    }
+
+    if cost.complexity > config.max_complexity {
+        return Err(format!("Query complexity {} exceeds the limit of {}", cost.complexity, config.max_complexity));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> QueryLimitsConfig {
+        QueryLimitsConfig { max_depth: 4, max_complexity: 1000, default_list_size: 20 }
+    }
+
+    fn cost(query: &str, variables: Option<serde_json::Value>) -> QueryCost {
+        let document = async_graphql_parser::parse_query(query).unwrap();
+        query_cost(&config(), &document, &None, &variables)
+    }
+
+    #[test]
+    fn counts_depth_and_scalar_fields() {
+        let cost = cost("{ myAccount { id name } }", None);
+        assert_eq!(cost.depth, 2);
+        assert_eq!(cost.complexity, 4);
+    }
+
+    #[test]
+    fn multiplies_list_children_by_limit() {
+        let cost = cost("{ post(id: 1) { comments(cursor: 0, limit: 10) { id mesg } } }", None);
+        assert_eq!(cost.complexity, 2 + 2 + 10 * 2);
+    }
+
+    #[test]
+    fn reads_limit_from_variables() {
+        let variables = Some(serde_json::json!({ "limit": 5 }));
+        let cost = cost("query($limit: Int!) { feed(cursor: 0, limit: $limit) { id } }", variables);
+        assert_eq!(cost.complexity, 2 + 5);
+    }
+
+    #[test]
+    fn clamps_limit_to_page_limit() {
+        let cost = cost("{ feed(cursor: 0, limit: 1000000000) { id } }", None);
+        assert_eq!(cost.complexity, 2 + MAX_PAGE_LIMIT as usize);
+    }
+
+    #[test]
+    fn saturates_instead_of_overflowing() {
+        let mut query = "id".to_string();
+        for _ in 0..40 {
+            query = format!("feed(cursor: 0, limit: 100) {{ {} }}", query);
+        }
+
+        let cost = cost(&format!("{{ {} }}", query), None);
+        assert_eq!(cost.complexity, usize::MAX);
+    }
+
+    //the query graphiql and the playground send on load
+    const INTROSPECTION_QUERY: &str = r#"
+        query IntrospectionQuery {
+          __schema {
+            queryType { name }
+            mutationType { name }
+            subscriptionType { name }
+            types { ...FullType }
+            directives { name description locations args { ...InputValue } }
+          }
+        }
+
+        fragment FullType on __Type {
+          kind name description
+          fields(includeDeprecated: true) {
+            name description
+            args { ...InputValue }
+            type { ...TypeRef }
+            isDeprecated deprecationReason
+          }
+          inputFields { ...InputValue }
+          interfaces { ...TypeRef }
+          enumValues(includeDeprecated: true) { name description isDeprecated deprecationReason }
+          possibleTypes { ...TypeRef }
+        }
+
+        fragment InputValue on __InputValue {
+          name description
+          type { ...TypeRef }
+          defaultValue
+        }
+
+        fragment TypeRef on __Type {
+          kind name
+          ofType { kind name ofType { kind name ofType { kind name ofType { kind name
+            ofType { kind name ofType { kind name ofType { kind name } } } } } } }
+        }
+    "#;
+
+    #[test]
+    fn introspection_is_free_and_has_its_own_depth() {
+        let cost = cost("{ __schema { types { fields { type { name } } } } }", None);
+        assert_eq!(cost.depth, 0);
+        assert_eq!(cost.introspection_depth, 5);
+        assert_eq!(cost.complexity, 0);
+        assert!(check_limits(&config(), "{ __schema { types { fields { type { name } } } } }", &None, &None).is_ok());
+    }
+
+    #[test]
+    fn standard_introspection_query_passes_default_limits() {
+        let document = async_graphql_parser::parse_query(INTROSPECTION_QUERY).unwrap();
+        let cost = query_cost(&QueryLimitsConfig::default(), &document, &None, &None);
+        assert_eq!(cost.introspection_depth, 13);
+        assert!(check_limits(&QueryLimitsConfig::default(), INTROSPECTION_QUERY, &None, &None).is_ok());
+    }
+
+    #[test]
+    fn deep_introspection_is_rejected() {
+        let mut query = "name".to_string();
+        for _ in 0..MAX_INTROSPECTION_DEPTH {
+            query = format!("ofType {{ {} }}", query);
+        }
+
+        let query = format!("{{ __type(name: \"Post\") {{ {} }} }}", query);
+        assert!(check_limits(&QueryLimitsConfig::default(), &query, &None, &None).is_err());
+    }
+
+    #[test]
+    fn typename_does_not_add_depth() {
+        let cost = cost("{ myAccount { id __typename } }", None);
+        assert_eq!(cost.depth, 2);
+        assert_eq!(cost.complexity, 3);
+    }
+
+    #[test]
+    fn fragments_are_expanded() {
+        let cost = cost("{ myAccount { ...Names } } fragment Names on MyAccount { id name }", None);
+        assert_eq!(cost.complexity, 4);
+    }
+}
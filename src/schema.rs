@@ -1,16 +1,23 @@
 //use crate::context::Context;
-use crate::context::{get_db, get_shared};
+use crate::context::{get_db, get_shared, get_redis};
+use crate::cache::cached;
+use crate::telemetry::sql_span;
 use crate::dataloaders::*;
 use data::dataloader::ID;
-use data::sql_resolve::{SQLResolve, SQLTable};
+use data::sql_resolve::{SQLResolve, SQLTable, Page};
+use data::query::{Select, Order};
 use data_macros::*;
 use data_derive::*;
-use crate::prof::*;
-use crate::auth::{MutationAuth, get_auth};
+use crate::auth::{Auth, MutationAuth, get_auth};
+use crate::errors;
 use crate::chat::{QueryChats, MutationChat};
 use crate::explore::QueryExplore;
 use crate::analytics::{QueryAnalytics, MutationAnalytics};
 use crate::followers::{QueryFollowers, MutationFollowers};
+use crate::image::{Image, MutationImage};
+use crate::posts::{MutationPosts, post_key, POST_EXPIRY};
+use crate::comments::MutationComments;
+use crate::feed;
 use async_graphql::{Context, FieldResult, InputValueError, InputValueResult, ScalarType, EmptySubscription, Schema};
 use async_graphql_derive::*;
 use chrono::{DateTime, Utc};
@@ -18,13 +25,18 @@ use std::error::Error;
 use std::vec::Vec;
 use sqlx::{query_as, query, Row};
 use log::info;
+use tracing::Instrument;
 use std::default::Default;
+use serde::{Serialize, Deserialize};
 
 
-pub type Image = i32;
+pub type ImageID = i32;
 
 type Timestamp = DateTime<Utc>;
 
+const LIKERS_PAGE_LIMIT: i64 = 50;
+const COMMENTS_PAGE_LIMIT: i64 = 20;
+
 
 /*
 impl<'a> FromSql<'a> for Timestamp {
@@ -89,11 +101,62 @@ impl QueryMyAccount {
 pub struct Comment {
     pub id: ID,
     pub account: ID,
+    pub post: ID,
+    pub parent: Option<ID>,
     pub mesg: String,
     pub sent: DateTime<Utc>,
+    pub edited: Option<DateTime<Utc>>,
+    pub hidden: bool,
+}
+
+//comments are paged by id, which follows the order they were sent in. Unlike a lookup of the
+//cursor comment, this keeps working when the last comment of the previous page was deleted
+fn comment_page(select: Select<Comment>, cursor: Option<i64>, limit: Option<i64>) -> Select<Comment> {
+    select
+        .order_by("id", Order::Asc)
+        .after(cursor)
+        .limit(limit.unwrap_or(COMMENTS_PAGE_LIMIT).min(COMMENTS_PAGE_LIMIT))
+}
+
+impl Comment {
+    async fn load_reply_count(&self, context: &Context<'_>) -> FieldResult<i64> {
+        Ok(query_count!(context, "SELECT COUNT(id) FROM Comments WHERE parent = $1 AND NOT hidden", self.id))
+    }
+}
+
+//counts are null when loading them failed, the error is returned next to the rest of the data
+#[Object]
+impl Comment {
+    pub async fn id(&self) -> ID { self.id }
+    pub async fn mesg(&self) -> &str { &self.mesg }
+    pub async fn sent(&self) -> DateTime<Utc> { self.sent }
+    pub async fn edited(&self) -> Option<DateTime<Utc>> { self.edited }
+    pub async fn parent_id(&self) -> Option<ID> { self.parent }
+    pub async fn hidden(&self) -> bool { self.hidden }
+
+    pub async fn account(&self, context: &Context<'_>) -> FieldResult<Account> {
+        let mut account_loader = get_loaders(context).account.clone();
+        Ok(account_loader.load(self.account).await?)
+    }
+
+    pub async fn reply_count(&self, context: &Context<'_>) -> Option<i64> {
+        errors::nullable(context, self.load_reply_count(context).await.map(Some))
+    }
+
+    pub async fn replies(&self, context: &Context<'_>, cursor: Option<i64>, limit: Option<i64>) -> FieldResult<Vec<Comment>> {
+        let select = Select::<Comment>::new()
+            .eq("parent", self.id)
+            .where_sql("NOT Comments.hidden", None::<i32>);
+
+        comment_page(select, cursor, limit)
+            .fetch_all(context, get_db(context))
+            .instrument(sql_span("schema.replies"))
+            .await
+    }
 }
 
-#[sql("Post")]
+#[sql("Posts")]
+#[derive(Serialize, Deserialize)]
 pub struct Post {
     pub id: i32,
     pub account: ID,
@@ -102,21 +165,54 @@ pub struct Post {
     pub image: i32,
 }
 
+impl Post {
+    async fn load_likes(&self, context: &Context<'_>) -> FieldResult<i32> {
+        let mut loader = get_loaders(context).like_count.clone();
+        Ok(loader.load(self.id).await? as i32)
+    }
+
+    async fn load_liked_by_me(&self, context: &Context<'_>) -> FieldResult<bool> {
+        let mut loader = match get_viewer_loaders(context) {
+            Some(loaders) => loaders.liked_by_me(),
+            None => return Ok(false),
+        };
+
+        Ok(loader.load(self.id).await?)
+    }
+
+    async fn load_comment_count(&self, context: &Context<'_>) -> FieldResult<i64> {
+        let results = query!("SELECT COUNT(id) FROM Comments WHERE post=$1 AND NOT hidden", self.id)
+            .fetch_one(get_db(context))
+            .instrument(sql_span("schema.comment_count"))
+            .await?;
+
+        Ok(results.count.unwrap_or_else(|| 0))
+    }
+}
+
+//likes, likedByMe and commentCount are null when loading them failed, the error is returned next to the post
 #[Object]
 impl Post {
     pub async fn id(&self) -> i32 { self.id }
     pub async fn description(&self) -> &str { &self.description}
     pub async fn title(&self) -> &str { &self.title }
-    pub async fn image(&self) -> i32 { self.image }
-    pub async fn likes(&self, context: &Context<'_>) -> FieldResult<i32> {
-        let db = get_db(context);
-        let id: i32 = self.id;
+    pub async fn image(&self) -> Image { Image::new(self.image) }
+    pub async fn image_id(&self) -> ImageID { self.image }
+    pub async fn likes(&self, context: &Context<'_>) -> Option<i32> {
+        errors::nullable(context, self.load_likes(context).await.map(Some))
+    }
 
-        let row = query!("select count(id) FROM PostLikes where post = $1", id)
-            .fetch_one(db)
-            .await?;
+    //false for anonymous viewers
+    pub async fn liked_by_me(&self, context: &Context<'_>) -> Option<bool> {
+        errors::nullable(context, self.load_liked_by_me(context).await.map(Some))
+    }
 
-        Ok(row.count.unwrap() as i32)
+    //ordered by account id rather than by when the post was liked, the cursor is the id of the last account of the previous page
+    pub async fn likers(&self, context: &Context<'_>, cursor: Option<i64>, limit: Option<i64>) -> FieldResult<Vec<Account>> {
+        let page = Page::new("id", cursor, limit.unwrap_or(LIKERS_PAGE_LIMIT).min(LIKERS_PAGE_LIMIT));
+        Ok(select_all_from!(context, Account, "
+        WHERE Users.id IN (SELECT account FROM PostLikes WHERE post = $1)
+        ", page = page, self.id))
     }
 
     pub async fn account(&self, context: &Context<'_>) -> FieldResult<Account> {
@@ -133,25 +229,43 @@ impl Post {
         Ok(result)
     }
 
-    pub async fn comment_count(&self, context: &Context<'_>) -> FieldResult<i64> {
-        let results = query!("SELECT COUNT(id) FROM Comments WHERE post=$1", self.id)
-            .fetch_one(get_db(context))
-            .await?;
-
-        Ok(results.count.unwrap_or_else(|| 0))
+    pub async fn comment_count(&self, context: &Context<'_>) -> Option<i64> {
+        errors::nullable(context, self.load_comment_count(context).await.map(Some))
     }
 
-    pub async fn comments(&self, context: &Context<'_>, cursor: i64, limit: i64) -> FieldResult<Vec<Comment>> {
-        let db = get_db(context);
-        let id: i32 = self.id;
-        let results = query_as!(Comment, "SELECT id, account, mesg, sent FROM Comments WHERE post=$1 LIMIT $2", id, limit)
-            .fetch_all(db)
+    //top level comments oldest first, the cursor is the id of the last comment of the previous page, 0 for the first page
+    pub async fn comments(&self, context: &Context<'_>, cursor: i64, limit: Option<i64>) -> FieldResult<Vec<Comment>> {
+        let select = Select::<Comment>::new()
+            .eq("post", self.id)
+            .where_sql("Comments.parent IS NULL AND NOT Comments.hidden", None::<i32>);
+
+        let results = comment_page(select, Some(cursor), limit)
+            .fetch_all(context, get_db(context))
+            .instrument(sql_span("schema.comments"))
             .await?;
         Ok(results)
     }
+
+    //reported and hidden comments, only visible to the author of the post
+    pub async fn reported_comments(&self, context: &Context<'_>, cursor: Option<i64>, limit: Option<i64>) -> FieldResult<Vec<Comment>> {
+        let user = get_auth(context)?.user;
+        if user != self.account {
+            return Err(errors::forbidden("Only the author of the post can see reported comments"));
+        }
+
+        let select = Select::<Comment>::new()
+            .eq("post", self.id)
+            .where_sql("(Comments.hidden OR EXISTS (SELECT * FROM CommentReports WHERE comment = Comments.id))", None::<i32>);
+
+        comment_page(select, cursor, limit)
+            .fetch_all(context, get_db(context))
+            .instrument(sql_span("schema.reported_comments"))
+            .await
+    }
 }
 
 
+#[sql("Bonds")]
 pub struct Bond {
     pub id: ID,
     pub image: i32,
@@ -176,7 +290,8 @@ impl Bond {
     pub async fn id(&self) -> ID { self.id }
     pub async fn description(&self) -> &str { &self.description }
     pub async fn price(&self) -> i32 { self.price }
-    pub async fn image(&self) -> i32 { self.image }
+    pub async fn image(&self) -> Image { Image::new(self.image) }
+    pub async fn image_id(&self) -> ImageID { self.image }
     pub async fn sdgs(&self) -> &[i32] { &self.sdgs }
     pub async fn title(&self) -> &str { &self.title }
     pub async fn issuer(&self) -> &str { &self.issuer }
@@ -202,11 +317,12 @@ pub struct ProjectMember {
     joined: DateTime<Utc>
 }
 
+#[sql("Projects")]
 pub struct Project {
     pub id: i32,
     pub name: String,
     pub description: String,
-    pub image: Image,
+    pub image: ImageID,
     pub sdgs: Vec<i32>,
     pub latitude: f64,
     pub longitude: f64
@@ -216,12 +332,13 @@ impl Project {
     pub async fn id(&self) -> i32 { self.id }
     pub async fn title(&self) -> &str { &self.name } //todo change database field to title
     pub async fn description(&self) -> &str { &self.description }
-    pub async fn image(&self) -> i32 { self.image }
+    pub async fn image(&self) -> Image { Image::new(self.image) }
+    pub async fn image_id(&self) -> ImageID { self.image }
     pub async fn sdgs(&self) -> &[i32] { &self.sdgs }
     pub async fn latitude(&self) -> f64 { self.latitude }
     pub async fn longitude(&self) -> f64 { self.longitude }
     pub async fn posts(&self, ctx: &Context<'_>, cursor: i64, limit: i64) -> FieldResult<Vec<Post>> {
-        Ok(query_all_as!(ctx, Post, "select id, account, image, title, description from Posts where project = $1 LIMIT $2", self.id, limit))
+        Ok(select_all_from!(ctx, Post, "WHERE project = $1", page = Page::new("id", Some(cursor), limit), self.id))
     }
     pub async fn members(&self, ctx: &Context<'_>) -> FieldResult<Vec<ProjectMember>> {
         let results = query_all!(ctx, "select ProjectMembers.id, ProjectMembers.joined, ProjectMembers.role,
@@ -253,26 +370,26 @@ pub struct QueryFeed;
 #[Object]
 impl QueryFeed {
     async fn post(&self, ctx: &Context<'_>, id: ID) -> FieldResult<Post> {
-        let post = query_as!(Post, "select id, account, image, title, description from Posts where id = $1", id)
-            .fetch_one(get_db(ctx))
-            .await?;
-
-        Ok(post)
+        //missing posts are not cached, so a post is visible as soon as it is created
+        redis_cached!(ctx, &post_key(id), POST_EXPIRY, query_as!(Post, "select id, account, image, title, description from Posts where id = $1", id)
+            .fetch_optional(get_db(ctx))
+            .instrument(sql_span("schema.post"))
+            .await?
+            .ok_or_else(|| errors::not_found("No such post"))?)
     }
 
+    //cursor is the number of posts already loaded, it is an offset rather than a post id because the
+    //ranking changes as posts age. Pages are not a snapshot, so posts created or re-ranked between two
+    //requests can shift the order, clients should skip ids they already have and may miss a post
     async fn feed(&self, ctx: &Context<'_>, cursor: i32, limit: i32) -> FieldResult<Vec<Post>> {
         let db = &get_shared(ctx).db;
-        let mut prof = Prof::new();
-
-        let posts: Vec<Post> = query_as!(Post,
-                "select id, account, image, title, description from Posts",
-            )
-            .fetch_all(db)
-            .await?;
-
-        prof.log("Load feed");
+        let offset = cursor.max(0) as i64;
+        let limit = (limit as i64).max(0).min(feed::MAX_FEED_LIMIT);
 
-        Ok(posts)
+        match ctx.data::<Auth>() {
+            Ok(auth) if auth.personalization => feed::ranked_feed(db, auth.user, offset, limit).await,
+            _ => feed::chronological_feed(db, offset, limit).await,
+        }
     }
 }
 
@@ -281,7 +398,7 @@ impl QueryFeed {
 pub struct QueryRoot(pub QueryFeed, pub QueryExplore, pub QueryChats, pub QueryAnalytics, pub QueryMyAccount);
 
 #[derive(async_graphql::GQLMergedObject, Default)]
-pub struct MutationRoot(pub MutationAuth, pub MutationChat, pub MutationAnalytics, pub MutationFollowers);
+pub struct MutationRoot(pub MutationAuth, pub MutationChat, pub MutationAnalytics, pub MutationFollowers, pub MutationImage, pub MutationPosts, pub MutationComments);
 
 /*
 pub struct SubscriptionRoot;
@@ -0,0 +1,59 @@
+use crate::config::{LogConfig, LogFormat};
+use hyper::http::HeaderValue;
+use hyper::{Body, Request};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+//sqlx statement logging is turned off in make_db, queries are traced through sql_span instead
+pub fn init(config: &LogConfig) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&config.filter));
+
+    match config.format {
+        LogFormat::Json => builder.json().try_init(),
+        LogFormat::Pretty => builder.try_init(),
+    }
+}
+
+//only the statement name is recorded, parameter values can contain personal data
+pub fn sql_span(statement: &'static str) -> Span {
+    tracing::debug_span!("sql", statement)
+}
+
+fn generate_request_id() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .collect()
+}
+
+//reuses the caller's id so requests can be followed across services
+pub fn request_id(req: &Request<Body>) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(|id| id.to_string())
+        .unwrap_or_else(generate_request_id)
+}
+
+pub fn request_id_header(id: &str) -> HeaderValue {
+    HeaderValue::from_str(id).unwrap_or_else(|_| HeaderValue::from_static("invalid"))
+}
+
+//variables can contain passwords and personal data, only their names are logged
+pub fn redact_variables(variables: &Option<serde_json::Value>) -> serde_json::Value {
+    match variables {
+        Some(serde_json::Value::Object(map)) => serde_json::Value::Object(
+            map.keys()
+                .map(|key| (key.clone(), serde_json::Value::String("[redacted]".to_string())))
+                .collect(),
+        ),
+        _ => serde_json::Value::Null,
+    }
+}